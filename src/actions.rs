@@ -6,15 +6,20 @@
 //! even if one of the later actions fail.
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-pub use borg::BorgBackup;
-pub use runner::Runner;
-pub use snaps::{LvmRsure, LvmSnapshot, MountSnap, SimpleRsure, Stamp};
-pub use zfs::{Rsync, ZfsSnapshot};
+pub use borg::{BorgBackup, BorgStats};
+pub use events::{Event, Observer, Progress};
+pub use runner::{Policy, Retry, Runner};
+pub use snaps::{LvmRsure, LvmSnapshot, MountSnap, RsureStats, SimpleRsure, Stamp};
+pub use summary::{ActionResult, Summary};
+pub use zfs::{Rsync, ZfsPrune, ZfsReplicate, ZfsSnapshot};
 
 mod borg;
+mod events;
 mod runner;
 mod snaps;
+mod summary;
 mod zfs;
 
 pub trait Action {
@@ -23,6 +28,38 @@ pub trait Action {
 
     /// Return a description of this action.
     fn describe(&self) -> String;
+
+    /// Return any statistics gathered while performing this action.  Most
+    /// actions don't gather any.
+    fn stats(&self) -> Option<Stats> {
+        None
+    }
+
+    /// Can this action be retried when it fails?  Actions that opt in to
+    /// this must be safe to perform again after a failed attempt.
+    fn retryable(&self) -> bool {
+        false
+    }
+
+    /// Show what performing this action would do, for a pretend run.
+    /// Actions that can find out more without changing anything may show
+    /// more than the description.
+    fn pretend(&self) -> Result<()> {
+        println!("would: {}", self.describe());
+        Ok(())
+    }
+}
+
+/// Statistics that an action can report about the work it performed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stats {
+    Borg(BorgStats),
+    Rsure(RsureStats),
+    /// The name of a snapshot that was made.
+    Snapshot(String),
+    /// The estimated number of bytes sent by ZFS replication.
+    ZfsSend(u64),
 }
 
 /// A very simple action that just prints a separator describing a block of
@@ -1,11 +1,12 @@
 // SPDX-License-Identifier: Apache-2.0
 //! Actions related to borg backup
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::info;
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use std::process::{Command, Stdio};
 
-use super::Action;
+use super::{Action, Stats};
 use crate::checked::CheckedExt;
 
 /// An action that performs a borg backup.  This needs a path to a borg
@@ -20,6 +21,31 @@ pub struct BorgBackup {
 
     /// The name appended to the backup.
     name: String,
+
+    /// How many times the create has been attempted.
+    attempts: u32,
+
+    /// The statistics borg reported for the archive, once it has been
+    /// created.
+    stats: Option<BorgStats>,
+}
+
+/// Statistics about a single created archive, as reported by `borg create
+/// --json`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BorgStats {
+    /// The name of the archive created.
+    pub archive: String,
+    /// The total size of the files backed up.
+    pub original_size: u64,
+    /// The size after compression.
+    pub compressed_size: u64,
+    /// The size of the data that was new to the repository.
+    pub deduplicated_size: u64,
+    /// The number of files in the archive.
+    pub nfiles: u64,
+    /// How long borg took to create the archive, in seconds.
+    pub duration: f64,
 }
 
 impl BorgBackup {
@@ -28,24 +54,45 @@ impl BorgBackup {
             snap: snap.into(),
             script: script.into(),
             name: name.into(),
+            attempts: 0,
+            stats: None,
         })
     }
+
+    /// The name of the archive made by the latest attempt, or to be made
+    /// by the first.
+    fn archive(&self) -> String {
+        if self.attempts <= 1 {
+            self.name.clone()
+        } else {
+            format!("{}.{}", self.name, self.attempts)
+        }
+    }
 }
 
 impl Action for BorgBackup {
     fn perform(&mut self) -> Result<()> {
-        info!("Running borg backup of {} via {}", self.snap, self.name);
-        Command::new(&self.script)
+        // An attempt that failed may still have committed its archive, so
+        // each retry uses a new name, with the number of the attempt.
+        self.attempts += 1;
+        let archive = self.archive();
+        info!("Running borg backup of {} via {}", self.snap, archive);
+        // With `--json`, borg writes the statistics to stdout, and the
+        // progress still goes to stderr.
+        let out = Command::new(&self.script)
             .args(&[
                 "create",
                 "--exclude-caches",
                 "-x",
-                "--stat",
+                "--json",
                 "--progress",
-                &format!("::{}", self.name),
+                &format!("::{}", archive),
                 &self.snap,
             ])
-            .checked_noio()?;
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .checked_output()?;
+        self.stats = Some(BorgStats::parse(&out.stdout)?);
         Ok(())
     }
 
@@ -55,6 +102,143 @@ impl Action for BorgBackup {
     }
 
     fn describe(&self) -> String {
-        format!("Borg backup of {} to {}", self.snap, self.name)
+        format!("Borg backup of {} to {}", self.snap, self.archive())
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        self.stats.clone().map(Stats::Borg)
+    }
+
+    // A failed create leaves at most a checkpoint archive, or if it failed
+    // after committing, the archive itself.  Neither is in the way of a
+    // retry, which uses a new name.
+    fn retryable(&self) -> bool {
+        true
+    }
+}
+
+impl BorgStats {
+    /// Decode the json output of `borg create --json`.
+    pub fn parse(json: &[u8]) -> Result<BorgStats> {
+        let out: CreateOutput = serde_json::from_slice(json)
+            .map_err(|e| anyhow!("Unable to parse borg create output: {}", e))?;
+        Ok(BorgStats {
+            archive: out.archive.name,
+            original_size: out.archive.stats.original_size,
+            compressed_size: out.archive.stats.compressed_size,
+            deduplicated_size: out.archive.stats.deduplicated_size,
+            nfiles: out.archive.stats.nfiles,
+            duration: out.archive.duration,
+        })
+    }
+}
+
+// The parts of the `borg create --json` output that we care about.  Borg
+// includes information about the repository and cache as well, which is
+// ignored.
+#[derive(Deserialize)]
+struct CreateOutput {
+    archive: ArchiveOutput,
+}
+
+#[derive(Deserialize)]
+struct ArchiveOutput {
+    name: String,
+    duration: f64,
+    stats: ArchiveStats,
+}
+
+#[derive(Deserialize)]
+struct ArchiveStats {
+    original_size: u64,
+    compressed_size: u64,
+    deduplicated_size: u64,
+    nfiles: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The form of the output of `borg create --json` from borg 1.2.
+    static CREATE: &str = r#"{
+    "archive": {
+        "command_line": [
+            "/usr/bin/borg",
+            "create",
+            "--exclude-caches",
+            "-x",
+            "--json",
+            "--progress",
+            "::home-20210601T120000",
+            "/mnt/snap/home"
+        ],
+        "duration": 12.345678,
+        "end": "2021-06-01T12:00:13.000000",
+        "id": "0f7e3ae3fbd6a34c7e6e4d2f4a2c2fb9ff79e2e2f5c8d4c3d3b2a1f0e9d8c7b6",
+        "limits": {
+            "max_archive_size": 0.0001
+        },
+        "name": "home-20210601T120000",
+        "start": "2021-06-01T12:00:00.654321",
+        "stats": {
+            "compressed_size": 734003200,
+            "deduplicated_size": 1048576,
+            "nfiles": 12345,
+            "original_size": 1073741824
+        }
+    },
+    "cache": {
+        "path": "/root/.cache/borg/1a2b3c",
+        "stats": {
+            "total_chunks": 250000,
+            "total_csize": 73400320000,
+            "total_size": 107374182400,
+            "total_unique_chunks": 60000,
+            "unique_csize": 7340032000,
+            "unique_size": 10737418240
+        }
+    },
+    "encryption": {
+        "mode": "repokey-blake2"
+    },
+    "repository": {
+        "id": "1a2b3c",
+        "last_modified": "2021-06-01T12:00:13.000000",
+        "location": "ssh://backup@example.com/./repo"
+    }
+}"#;
+
+    #[test]
+    fn parse() {
+        let stats = BorgStats::parse(CREATE.as_bytes()).unwrap();
+        assert_eq!(stats.archive, "home-20210601T120000");
+        assert_eq!(stats.original_size, 1073741824);
+        assert_eq!(stats.compressed_size, 734003200);
+        assert_eq!(stats.deduplicated_size, 1048576);
+        assert_eq!(stats.nfiles, 12345);
+        assert!((stats.duration - 12.345678).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(BorgStats::parse(b"").is_err());
+        assert!(BorgStats::parse(br#"{"archive": {"name": "home"}}"#).is_err());
+    }
+
+    #[test]
+    fn retry_names() {
+        let _serial = crate::cancel::serial();
+        let mut backup =
+            BorgBackup::new("/mnt/snap/home", "false", "home-20210601T120000").unwrap();
+        let describe = "Borg backup of /mnt/snap/home to home-20210601T120000";
+        assert_eq!(backup.describe(), describe);
+        assert!(backup.perform().is_err());
+        assert_eq!(backup.describe(), describe);
+        // Each retry makes a new archive, which the description follows.
+        assert!(backup.perform().is_err());
+        assert_eq!(backup.describe(), format!("{}.2", describe));
+        assert!(backup.perform().is_err());
+        assert_eq!(backup.describe(), format!("{}.3", describe));
     }
 }
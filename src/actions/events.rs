@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Run events.
+//!
+//! As the runner performs actions, it reports what it is doing to any
+//! registered observers.  Reporting, history, metrics and notifications
+//! are all implemented as observers.
+
+use anyhow::Error;
+use log::{error, info};
+
+use super::{Stats, Summary};
+
+/// Something that happened during a run.  The `volume` is the volume the
+/// action was performed for, if any, and `description` is the action's
+/// description.
+pub enum Event<'a> {
+    /// The run is starting, with the given number of actions.
+    RunStart { actions: usize },
+    ActionStart {
+        volume: Option<&'a str>,
+        description: &'a str,
+    },
+    ActionSuccess {
+        volume: Option<&'a str>,
+        description: &'a str,
+        duration: f64,
+        stats: Option<&'a Stats>,
+    },
+    ActionFailure {
+        volume: Option<&'a str>,
+        description: &'a str,
+        duration: f64,
+        error: &'a Error,
+    },
+    CleanupStart {
+        volume: Option<&'a str>,
+        description: &'a str,
+    },
+    CleanupResult {
+        volume: Option<&'a str>,
+        description: &'a str,
+        error: Option<&'a Error>,
+    },
+    /// The run has finished, including all cleanups.
+    RunEnd { summary: &'a Summary },
+}
+
+/// An observer is told about each event during a run.  Observers are only
+/// used for runs that actually perform their actions, not for pretend
+/// runs.
+pub trait Observer {
+    fn notify(&mut self, event: &Event);
+}
+
+/// An observer that logs the progress of the run.
+pub struct Progress {
+    total: usize,
+    current: usize,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress {
+            total: 0,
+            current: 0,
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+impl Observer for Progress {
+    fn notify(&mut self, event: &Event) {
+        match *event {
+            Event::RunStart { actions } => self.total = actions,
+            Event::ActionStart { description, .. } => {
+                self.current += 1;
+                info!("[{}/{}] {}", self.current, self.total, description);
+            }
+            Event::ActionSuccess {
+                description,
+                duration,
+                ..
+            } => info!(
+                "[{}/{}] done in {:.1}s: {}",
+                self.current, self.total, duration, description
+            ),
+            Event::ActionFailure {
+                description,
+                duration,
+                error: err,
+                ..
+            } => error!(
+                "[{}/{}] failed after {:.1}s: {}: {:#}",
+                self.current, self.total, duration, description, err
+            ),
+            Event::CleanupResult {
+                description,
+                error: Some(err),
+                ..
+            } => error!("Cleanup failed: {}: {:#}", description, err),
+            _ => (),
+        }
+    }
+}
@@ -5,71 +5,603 @@
 //! a backup.  Each of these actions has a possible cleanup.  We want to
 //! run the cleanup on all actions that have completed, regardless of any
 //! errors that may have happened.
+//!
+//! Each action may be given a timeout, after which any command it is
+//! running is killed, and the action fails.  A SIGINT or SIGTERM (once
+//! `cancel::install` has been called) fails the current action in the
+//! same way.  Actions that do their work within rdump can't be stopped,
+//! so they fail when they finish, if they ran past their deadline or were
+//! cancelled.  In either case, the cleanups are still run.
+//!
+//! Actions that can fail for transient reasons, such as those that reach
+//! other hosts over ssh, can opt in to being retried.  The runner will
+//! then retry them according to their `Retry` policy.
 
-use super::Action;
-use anyhow::Result;
+use super::{Action, ActionResult, Event, Observer, Summary};
+use crate::{cancel, period::Period};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use log::warn;
+use serde::Deserialize;
+use std::{convert::TryFrom, thread, time::Instant};
 
 pub struct Runner {
-    actions: Vec<Box<dyn Action>>,
+    actions: Vec<Entry>,
+    observers: Vec<Box<dyn Observer>>,
+}
+
+/// An action, along with the name of the volume it operates on.
+struct Entry {
+    volume: Option<String>,
+    policy: Policy,
+    action: Box<dyn Action>,
+}
+
+/// Limits on how an action is performed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Policy {
+    /// How long each attempt may take before it is stopped.
+    pub timeout: Option<Period>,
+    /// How to retry the action if it fails.  Only used for actions that
+    /// are `retryable`.
+    pub retry: Option<Retry>,
+}
+
+/// How to retry an action that fails.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "RawRetry")]
+pub struct Retry {
+    /// How many attempts to make, including the first.
+    pub attempts: u32,
+    /// How long to wait before the second attempt.
+    pub delay: Period,
+    /// How much to multiply the delay by for each further attempt.
+    pub backoff: f64,
+}
+
+/// A retry policy as given in the config, before it is checked.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetry {
+    attempts: u32,
+    #[serde(default = "default_delay")]
+    delay: Period,
+    #[serde(default = "default_backoff")]
+    backoff: f64,
+}
+
+/// The longest to wait between attempts, however far the delay has backed
+/// off.
+fn max_delay() -> Duration {
+    Duration::days(1)
 }
 
 impl Runner {
     pub fn new() -> Result<Runner> {
         Ok(Runner {
             actions: Vec::new(),
+            observers: Vec::new(),
         })
     }
 
     /// Add a new action, to be performed after previously added actions.
     pub fn push(&mut self, action: Box<dyn Action>) {
-        self.actions.push(action);
+        self.actions.push(Entry {
+            volume: None,
+            policy: Policy::default(),
+            action,
+        });
+    }
+
+    /// Add a new action that operates on behalf of the named volume.  The
+    /// volume name is used to group the results in the summary.
+    pub fn push_volume(&mut self, volume: &str, action: Box<dyn Action>) {
+        self.push_with(volume, Policy::default(), action);
+    }
+
+    /// Add a new action for the named volume, to be performed according
+    /// to the given policy.
+    pub fn push_with(&mut self, volume: &str, policy: Policy, action: Box<dyn Action>) {
+        self.actions.push(Entry {
+            volume: Some(volume.into()),
+            policy,
+            action,
+        });
+    }
+
+    /// Add an observer to be told about the events of (non-pretend) runs.
+    /// Observers are notified in the order they were added.
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
     }
 
     /// Perform all of the actions, and any appropriate cleanup.  Note that
     /// this consumes self, and all actions registered will be dropped.
-    /// If any perform results in an Error, that will be the return result
-    /// of this function, although cleanups will be called for other
-    /// actions.
-    pub fn run(self, pretend: bool) -> Result<()> {
+    /// If any perform results in an Error, the remaining actions are
+    /// skipped, although cleanups will be called for the actions that
+    /// were performed.  The returned summary describes what each action
+    /// did, and any error.
+    pub fn run(mut self, pretend: bool) -> Summary {
+        let mut summary = Summary::new();
+
+        for entry in &self.actions {
+            if let Some(ref volume) = entry.volume {
+                if !summary.volumes.contains(volume) {
+                    summary.volumes.push(volume.clone());
+                }
+            }
+        }
+
+        if pretend {
+            for entry in &self.actions {
+                if let Err(err) = entry.action.pretend() {
+                    log::error!("Error with pretend action: {:?}", err);
+                    summary.error = Some(format!("{:#}", err));
+                }
+            }
+            return summary;
+        }
+
+        let actions = std::mem::take(&mut self.actions);
+        self.emit(&Event::RunStart {
+            actions: actions.len(),
+        });
+
         let mut cleanups = vec![];
+        for mut entry in actions.into_iter() {
+            let description = entry.action.describe();
+            let volume = entry.volume.as_deref();
+            self.emit(&Event::ActionStart {
+                volume,
+                description: &description,
+            });
+
+            let start = Instant::now();
+            let result = Self::perform(entry.action.as_mut(), &entry.policy);
+            let duration = start.elapsed().as_secs_f64();
+            let stats = entry.action.stats();
+            // A retried action may have done something different, such as a
+            // borg backup to a new archive, so describe the last attempt.
+            let description = entry.action.describe();
 
-        for mut action in self.actions.into_iter() {
-            if pretend {
-                println!("would: {}", action.describe());
-            } else {
-                // TODO: Add a descriptive method.
-                match action.perform() {
-                    Ok(()) => cleanups.push(action),
-                    Err(err) => {
-                        log::error!("Error with action: {:?}", err);
-                        Self::run_cleanups(cleanups);
-                        return Err(err);
-                    }
+            match result {
+                Ok(()) => {
+                    self.emit(&Event::ActionSuccess {
+                        volume,
+                        description: &description,
+                        duration,
+                        stats: stats.as_ref(),
+                    });
+                    summary.push(ActionResult {
+                        volume: entry.volume.clone(),
+                        description,
+                        duration,
+                        stats,
+                        error: None,
+                    });
+                    cleanups.push(entry);
+                }
+                Err(err) => {
+                    log::error!("Error with action: {:?}", err);
+                    self.emit(&Event::ActionFailure {
+                        volume,
+                        description: &description,
+                        duration,
+                        error: &err,
+                    });
+                    summary.push(ActionResult {
+                        volume: entry.volume.clone(),
+                        description,
+                        duration,
+                        stats,
+                        error: Some(format!("{:#}", err)),
+                    });
+                    summary.error = Some(format!("{:#}", err));
+                    break;
                 }
             }
         }
 
-        Self::run_cleanups(cleanups);
+        summary.cleanup_errors = self.run_cleanups(cleanups);
+        summary.end = Utc::now();
+
+        self.emit(&Event::RunEnd { summary: &summary });
 
-        Ok(())
+        summary
+    }
+
+    /// Perform a single action, retrying it if its policy allows.
+    fn perform(action: &mut dyn Action, policy: &Policy) -> Result<()> {
+        let retry = match policy.retry {
+            Some(retry) if action.retryable() => retry,
+            _ => Retry::none(),
+        };
+        let timeout = policy.timeout.and_then(|t| t.duration().to_std().ok());
+
+        let mut delay = retry.delay.duration().min(max_delay());
+        let mut attempt = 1;
+        loop {
+            if cancel::cancelled() {
+                return Err(anyhow!("Cancelled"));
+            }
+            let result = {
+                let _scope = cancel::Scope::new(timeout);
+                action.perform().and_then(|()| cancel::check())
+            };
+            match result {
+                Err(err) if attempt < retry.attempts && !cancel::cancelled() => {
+                    warn!(
+                        "Attempt {}/{} of {} failed: {:#}",
+                        attempt,
+                        retry.attempts,
+                        action.describe(),
+                        err
+                    );
+                    warn!("Retrying in {}", Period::from_duration(delay));
+                    Self::sleep(delay);
+                    let next = delay.num_milliseconds() as f64 * retry.backoff;
+                    delay = if next < max_delay().num_milliseconds() as f64 {
+                        Duration::milliseconds(next as i64)
+                    } else {
+                        max_delay()
+                    };
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sleep for the given time, returning early if the run is cancelled.
+    fn sleep(time: Duration) {
+        let end = Utc::now() + time;
+        while Utc::now() < end && !cancel::cancelled() {
+            thread::sleep(std::time::Duration::from_millis(100));
+        }
     }
 
     /// Perform all of the given cleanups, in reverse order.  Errors are
     /// logged, but don't otherwise stop the rest of the cleanups from
-    /// running.
-    fn run_cleanups(mut cleanups: Vec<Box<dyn Action>>) {
-        while let Some(mut action) = cleanups.pop() {
-            // TODO: Add descriptive method.
-            match action.cleanup() {
-                Ok(()) => (),
-                Err(err) => log::error!("Cleanup error: {:?}", err),
+    /// running.  Returns the errors.
+    fn run_cleanups(&mut self, mut cleanups: Vec<Entry>) -> Vec<String> {
+        let mut errors = vec![];
+        while let Some(mut entry) = cleanups.pop() {
+            let description = entry.action.describe();
+            let volume = entry.volume.as_deref();
+            self.emit(&Event::CleanupStart {
+                volume,
+                description: &description,
+            });
+            let result = entry.action.cleanup();
+            self.emit(&Event::CleanupResult {
+                volume,
+                description: &description,
+                error: result.as_ref().err(),
+            });
+            if let Err(err) = result {
+                log::error!("Cleanup error: {:?}", err);
+                errors.push(format!("{}: {:#}", description, err));
             }
         }
+        errors
+    }
+
+    /// Tell all of the observers about an event.
+    fn emit(&mut self, event: &Event) {
+        for obs in &mut self.observers {
+            obs.notify(event);
+        }
+    }
+
+    /// The volume and description of each action, in order, for checking
+    /// what a runner was built with.
+    #[cfg(test)]
+    pub(crate) fn describe(&self) -> Vec<(Option<&str>, String)> {
+        self.actions
+            .iter()
+            .map(|e| (e.volume.as_deref(), e.action.describe()))
+            .collect()
     }
 
     /// Consume the argument, appending all actions from it into the self
     /// runner.
     pub fn append(&mut self, mut other: Runner) {
         self.actions.append(&mut other.actions);
+        self.observers.append(&mut other.observers);
+    }
+}
+
+impl Policy {
+    /// A policy with just a timeout.
+    pub fn timeout(timeout: Option<Period>) -> Policy {
+        Policy {
+            timeout,
+            retry: None,
+        }
+    }
+}
+
+impl Retry {
+    /// A policy of making only a single attempt.
+    fn none() -> Retry {
+        Retry {
+            attempts: 1,
+            delay: default_delay(),
+            backoff: default_backoff(),
+        }
+    }
+}
+
+impl TryFrom<RawRetry> for Retry {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawRetry) -> Result<Retry> {
+        if raw.attempts < 1 {
+            return Err(anyhow!("retry attempts must be at least 1"));
+        }
+        if !raw.backoff.is_finite() || raw.backoff < 1.0 {
+            return Err(anyhow!(
+                "retry backoff must be a number of at least 1, not {}",
+                raw.backoff
+            ));
+        }
+        Ok(Retry {
+            attempts: raw.attempts,
+            delay: raw.delay,
+            backoff: raw.backoff,
+        })
+    }
+}
+
+fn default_delay() -> Period {
+    Period::from_duration(Duration::seconds(60))
+}
+
+fn default_backoff() -> f64 {
+    2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// What was done with the fake actions.
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    /// An action that fails its first `failures` attempts.
+    struct Fake {
+        name: &'static str,
+        failures: u32,
+        retryable: bool,
+        /// Cancel the run when an attempt fails.
+        cancels: bool,
+        cleanup_fails: bool,
+        log: Log,
+    }
+
+    impl Fake {
+        fn new(name: &'static str, failures: u32, log: &Log) -> Fake {
+            Fake {
+                name,
+                failures,
+                retryable: true,
+                cancels: false,
+                cleanup_fails: false,
+                log: log.clone(),
+            }
+        }
+    }
+
+    impl Action for Fake {
+        fn perform(&mut self) -> Result<()> {
+            self.log.borrow_mut().push(format!("perform {}", self.name));
+            if self.failures == 0 {
+                return Ok(());
+            }
+            self.failures -= 1;
+            if self.cancels {
+                cancel::set_cancelled(true);
+            }
+            Err(anyhow!("{} failed", self.name))
+        }
+
+        fn cleanup(&mut self) -> Result<()> {
+            self.log.borrow_mut().push(format!("cleanup {}", self.name));
+            if self.cleanup_fails {
+                return Err(anyhow!("{} cleanup failed", self.name));
+            }
+            Ok(())
+        }
+
+        fn describe(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn retryable(&self) -> bool {
+            self.retryable
+        }
+    }
+
+    fn policy(attempts: u32) -> Policy {
+        Policy {
+            timeout: None,
+            retry: Some(Retry {
+                attempts,
+                delay: "0s".parse().unwrap(),
+                backoff: 2.0,
+            }),
+        }
+    }
+
+    /// Perform the action with the policy, returning the number of
+    /// attempts made, and the error, if any.
+    fn attempts(mut action: Fake, policy: &Policy) -> (usize, Option<String>) {
+        let log = action.log.clone();
+        let result = Runner::perform(&mut action, policy);
+        let attempts = log.borrow().len();
+        (attempts, result.err().map(|e| e.to_string()))
+    }
+
+    #[test]
+    fn retries() {
+        let _serial = cancel::serial();
+        let log = Log::default();
+
+        // A success stops the retries.
+        assert_eq!(attempts(Fake::new("a", 0, &log), &policy(3)), (1, None));
+        log.borrow_mut().clear();
+        assert_eq!(attempts(Fake::new("a", 2, &log), &policy(3)), (3, None));
+        log.borrow_mut().clear();
+
+        // The last error is returned once the attempts run out.
+        assert_eq!(
+            attempts(Fake::new("a", 5, &log), &policy(3)),
+            (3, Some("a failed".into()))
+        );
+        log.borrow_mut().clear();
+
+        // Without a retry policy, only one attempt is made.
+        assert_eq!(
+            attempts(Fake::new("a", 1, &log), &Policy::default()),
+            (1, Some("a failed".into()))
+        );
+        log.borrow_mut().clear();
+    }
+
+    #[test]
+    fn not_retryable() {
+        let _serial = cancel::serial();
+        let log = Log::default();
+        let mut action = Fake::new("a", 1, &log);
+        action.retryable = false;
+        assert_eq!(attempts(action, &policy(3)), (1, Some("a failed".into())));
+    }
+
+    #[test]
+    fn cancelled() {
+        let _serial = cancel::serial();
+        let log = Log::default();
+
+        // A cancellation during an attempt stops any more, and no attempt
+        // is made once cancelled.  The request is cleared before checking,
+        // so that it can't be left for the other tests.
+        let mut action = Fake::new("a", 2, &log);
+        action.cancels = true;
+        let during = attempts(action, &policy(3));
+        log.borrow_mut().clear();
+        let after = attempts(Fake::new("a", 0, &log), &policy(3));
+        cancel::set_cancelled(false);
+
+        assert_eq!(during, (1, Some("a failed".into())));
+        assert_eq!(after, (0, Some("Cancelled".into())));
+    }
+
+    #[test]
+    fn retry_config() {
+        let retry: Retry = serde_yaml::from_str("{attempts: 3, delay: 10s}").unwrap();
+        assert_eq!(retry.attempts, 3);
+        assert_eq!(retry.delay.to_string(), "10s");
+        assert_eq!(retry.backoff, 2.0);
+
+        let error = |text| serde_yaml::from_str::<Retry>(text).unwrap_err().to_string();
+        assert_eq!(error("{attempts: 0}"), "retry attempts must be at least 1");
+        assert_eq!(
+            error("{attempts: 2, backoff: 0.5}"),
+            "retry backoff must be a number of at least 1, not 0.5"
+        );
+    }
+
+    /// An observer that records the events, as text.
+    struct Recorder(Log);
+
+    impl Observer for Recorder {
+        fn notify(&mut self, event: &Event) {
+            let text = match *event {
+                Event::RunStart { actions } => format!("run start: {}", actions),
+                Event::ActionStart {
+                    volume,
+                    description,
+                } => format!("start: {:?} {}", volume, description),
+                Event::ActionSuccess { description, .. } => format!("success: {}", description),
+                Event::ActionFailure {
+                    description, error, ..
+                } => format!("failure: {}: {}", description, error),
+                Event::CleanupStart { description, .. } => format!("cleanup: {}", description),
+                Event::CleanupResult {
+                    description, error, ..
+                } => format!(
+                    "cleanup result: {}: {:?}",
+                    description,
+                    error.map(|e| e.to_string())
+                ),
+                Event::RunEnd { summary } => {
+                    format!("run end: {:?} {:?}", summary.error, summary.cleanup_errors)
+                }
+            };
+            self.0.borrow_mut().push(text);
+        }
+    }
+
+    #[test]
+    fn events() {
+        let _serial = cancel::serial();
+        let (log, events) = (Log::default(), Log::default());
+        let mut runner = Runner::new().unwrap();
+        runner.add_observer(Box::new(Recorder(events.clone())));
+        runner.push(Box::new(Fake::new("a", 0, &log)));
+        let mut b = Fake::new("b", 0, &log);
+        b.cleanup_fails = true;
+        runner.push_volume("home", Box::new(b));
+        runner.push_volume("home", Box::new(Fake::new("c", 1, &log)));
+        runner.push_volume("root", Box::new(Fake::new("d", 0, &log)));
+        let summary = runner.run(false);
+
+        assert_eq!(summary.volumes, vec!["home", "root"]);
+        assert_eq!(summary.error.as_deref(), Some("c failed"));
+        assert_eq!(summary.results.len(), 3);
+        // The cleanups are still run after the failure, in reverse order,
+        // and the action after it is skipped.
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "perform a",
+                "perform b",
+                "perform c",
+                "cleanup b",
+                "cleanup a"
+            ]
+        );
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "run start: 4",
+                "start: None a",
+                "success: a",
+                "start: Some(\"home\") b",
+                "success: b",
+                "start: Some(\"home\") c",
+                "failure: c: c failed",
+                "cleanup: b",
+                "cleanup result: b: Some(\"b cleanup failed\")",
+                "cleanup: a",
+                "cleanup result: a: None",
+                "run end: Some(\"c failed\") [\"b: b cleanup failed\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn pretend_events() {
+        let _serial = cancel::serial();
+        let (log, events) = (Log::default(), Log::default());
+        let mut runner = Runner::new().unwrap();
+        runner.add_observer(Box::new(Recorder(events.clone())));
+        runner.push_volume("home", Box::new(Fake::new("a", 0, &log)));
+        let summary = runner.run(true);
+
+        assert_eq!(summary.volumes, vec!["home"]);
+        assert!(log.borrow().is_empty());
+        assert!(events.borrow().is_empty());
     }
 }
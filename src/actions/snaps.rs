@@ -6,11 +6,16 @@
 //! even if one of the later actions fail.
 
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
+use rsure::{Store, Version};
+use serde::{Deserialize, Serialize};
 use std::{fs::OpenOptions, io::Write, path::Path, process::Command};
 
-use super::Action;
-use crate::checked::CheckedExt;
+use super::{Action, Stats};
+use crate::{
+    checked::CheckedExt,
+    surefile::{self, Changes},
+};
 
 /// An action that creates a timestamp in the filesystem of question.  This
 /// is used by some backup tools to avoid issues with files that are
@@ -153,6 +158,17 @@ pub struct LvmRsure {
     base_mount: String,
     mount: String,
     name: String,
+    stats: Option<RsureStats>,
+}
+
+/// Statistics about an rsure scan, from the versions of the surefile.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RsureStats {
+    /// The number of files scanned.
+    pub files: u64,
+    /// The number of files added, removed, or changed since the previous
+    /// scan.  All of the files are new on the first scan.
+    pub changed: u64,
 }
 
 impl LvmRsure {
@@ -161,6 +177,7 @@ impl LvmRsure {
             base_mount: base_mount.into(),
             mount: mount.into(),
             name: name.into(),
+            stats: None,
         })
     }
 }
@@ -178,6 +195,7 @@ impl Action for LvmRsure {
         tags.insert("name".into(), self.name.clone());
 
         rsure::update(&self.mount, &*store, is_update, &tags)?;
+        self.stats = RsureStats::read(&*store, is_update);
 
         info!("Copy rsure file {} to {}", surefile, self.base_mount);
         // Use cp command for -p to preserve as much as possible.
@@ -195,11 +213,16 @@ impl Action for LvmRsure {
     fn describe(&self) -> String {
         format!("LVM2-based Rsure scan of {}", self.mount)
     }
+
+    fn stats(&self) -> Option<Stats> {
+        self.stats.clone().map(Stats::Rsure)
+    }
 }
 
 pub struct SimpleRsure {
     mount: String,
     name: String,
+    stats: Option<RsureStats>,
 }
 
 impl SimpleRsure {
@@ -207,6 +230,7 @@ impl SimpleRsure {
         Ok(SimpleRsure {
             mount: mount.into(),
             name: name.into(),
+            stats: None,
         })
     }
 }
@@ -223,6 +247,7 @@ impl Action for SimpleRsure {
         tags.insert("name".into(), self.name.clone());
 
         rsure::update(&self.mount, &*store, is_update, &tags)?;
+        self.stats = RsureStats::read(&*store, is_update);
 
         Ok(())
     }
@@ -235,4 +260,35 @@ impl Action for SimpleRsure {
     fn describe(&self) -> String {
         format!("Simple Rsure scan of {}", self.mount)
     }
+
+    fn stats(&self) -> Option<Stats> {
+        self.stats.clone().map(Stats::Rsure)
+    }
+}
+
+impl RsureStats {
+    /// Compare the version just written to the surefile with the one
+    /// before it.  Failing to read them doesn't fail the scan, there are
+    /// just no statistics.
+    fn read(store: &dyn Store, is_update: bool) -> Option<RsureStats> {
+        let read = || -> Result<RsureStats> {
+            let latest = surefile::load(store, Version::Latest)?;
+            let prior = if is_update {
+                surefile::load(store, Version::Prior)?
+            } else {
+                surefile::Files::new()
+            };
+            Ok(RsureStats {
+                files: latest.len() as u64,
+                changed: Changes::between(&prior, &latest).count() as u64,
+            })
+        };
+        match read() {
+            Ok(stats) => Some(stats),
+            Err(err) => {
+                warn!("Unable to read rsure statistics: {:#}", err);
+                None
+            }
+        }
+    }
 }
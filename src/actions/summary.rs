@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Run summaries.
+//!
+//! As the runner performs actions, it collects the results of each one
+//! into a summary.  This can be shown as a table at the end of the run, or
+//! written out in a machine-readable form.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::Write, path::Path};
+
+use super::Stats;
+use crate::zfs::humanize_size;
+
+/// The result of a single action performed by the runner.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ActionResult {
+    /// The volume this action was performed on behalf of, if any.
+    pub volume: Option<String>,
+    /// The description of the action.
+    pub description: String,
+    /// How long the action took to perform, in seconds.
+    pub duration: f64,
+    /// Any statistics gathered by the action.
+    pub stats: Option<Stats>,
+    /// The error from the action, if it failed.
+    pub error: Option<String>,
+}
+
+/// The collected results of a run.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Summary {
+    /// When the run started and finished.
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// The volumes that the run was to back up.
+    pub volumes: Vec<String>,
+    pub results: Vec<ActionResult>,
+    /// The error that stopped the run, if any.
+    pub error: Option<String>,
+    /// Errors from cleanups, which don't stop the run.
+    #[serde(default)]
+    pub cleanup_errors: Vec<String>,
+}
+
+impl Default for Summary {
+    fn default() -> Summary {
+        let now = Utc::now();
+        Summary {
+            start: now,
+            end: now,
+            volumes: vec![],
+            results: vec![],
+            error: None,
+            cleanup_errors: vec![],
+        }
+    }
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Summary::default()
+    }
+
+    pub fn push(&mut self, result: ActionResult) {
+        self.results.push(result);
+    }
+
+    /// Did the run complete without error?
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Convert the outcome of the run into a Result.
+    pub fn check(&self) -> Result<()> {
+        match self.error {
+            Some(ref err) => Err(anyhow!("Backup failed: {}", err)),
+            None => Ok(()),
+        }
+    }
+
+    /// The names of the snapshots made during the run.
+    pub fn snapshots(&self) -> Vec<String> {
+        self.results
+            .iter()
+            .filter_map(|r| match r.stats {
+                Some(Stats::Snapshot(ref name)) if r.error.is_none() => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Print a table of the borg statistics, one line per volume.
+    pub fn show(&self) {
+        print!("{}", self.borg_table());
+    }
+
+    /// Format a table of the borg statistics, one line per volume.  Empty
+    /// if no borg backups were made.
+    pub fn borg_table(&self) -> String {
+        let borgs: Vec<_> = self
+            .results
+            .iter()
+            .filter_map(|r| match r.stats {
+                Some(Stats::Borg(ref st)) => Some((r.volume.as_deref().unwrap_or("-"), st)),
+                _ => None,
+            })
+            .collect();
+        if borgs.is_empty() {
+            return String::new();
+        }
+
+        let mut out = format!(
+            "{:<16} {:>12} {:>12} {:>12} {:>10} {:>9}\n",
+            "volume", "original", "compressed", "dedup", "files", "seconds"
+        );
+        for (volume, st) in borgs {
+            out.push_str(&format!(
+                "{:<16} {:>12} {:>12} {:>12} {:>10} {:>9.1}\n",
+                volume,
+                humanize_size(st.original_size as usize),
+                humanize_size(st.compressed_size as usize),
+                humanize_size(st.deduplicated_size as usize),
+                st.nfiles,
+                st.duration
+            ));
+        }
+        out
+    }
+
+    /// Format a plain text report of the run, suitable for mailing.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!(
+            "Outcome:  {}\n",
+            if self.success() { "success" } else { "FAILED" }
+        ));
+        out.push_str(&format!(
+            "Started:  {}\n",
+            self.start.format("%Y-%m-%d %H:%M:%S UTC")
+        ));
+        out.push_str(&format!(
+            "Elapsed:  {}m {}s\n",
+            (self.end - self.start).num_minutes(),
+            (self.end - self.start).num_seconds() % 60
+        ));
+        out.push_str(&format!("Volumes:  {}\n", self.volumes.join(", ")));
+        if let Some(ref err) = self.error {
+            out.push_str(&format!("Error:    {}\n", err));
+        }
+        for err in &self.cleanup_errors {
+            out.push_str(&format!("Cleanup:  {}\n", err));
+        }
+
+        out.push_str("\nActions:\n");
+        for res in &self.results {
+            out.push_str(&format!(
+                "  {:>8.1}s  {}  {}\n",
+                res.duration,
+                if res.error.is_none() {
+                    "ok    "
+                } else {
+                    "FAILED"
+                },
+                res.description
+            ));
+            if let Some(ref err) = res.error {
+                out.push_str(&format!("             {}\n", err));
+            }
+        }
+
+        let table = self.borg_table();
+        if !table.is_empty() {
+            out.push('\n');
+            out.push_str(&table);
+        }
+        out
+    }
+
+    /// Write this summary, as json, to the given file.
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(&mut file)?;
+        Ok(())
+    }
+}
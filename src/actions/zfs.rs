@@ -8,8 +8,13 @@ use anyhow::Result;
 use log::{error, info};
 use std::process::{Command, Stdio};
 
-use super::Action;
-use crate::checked::CheckedExt;
+use super::{Action, Stats};
+use crate::{
+    cancel,
+    checked::CheckedExt,
+    retention::Retention,
+    zfs::{SendOptions, Zfs},
+};
 
 static ZFS: &'static str = "/usr/sbin/zfs";
 static RSYNC: &'static str = "/usr/bin/rsync";
@@ -53,7 +58,8 @@ impl Action for Rsync {
         // proceed.
         cmd.stderr(Stdio::inherit());
         cmd.stdin(Stdio::null());
-        let status = cmd.status()?;
+        let mut child = cancel::spawn(&mut cmd)?;
+        let status = cancel::wait(&mut child)?;
         if !status.success() {
             error!("Error running command: {:?} ({:?}", cmd, status);
             error!("Continuing past rsync error");
@@ -104,4 +110,172 @@ impl Action for ZfsSnapshot {
     fn describe(&self) -> String {
         format!("Zfs snapshot {}@{}", self.volume, self.snap)
     }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(Stats::Snapshot(format!("{}@{}", self.volume, self.snap)))
+    }
+}
+
+/// An action that replicates a tree of ZFS filesystems, along with their
+/// snapshots, to another location, possibly on another host.
+pub struct ZfsReplicate {
+    src_host: Option<String>,
+    src: String,
+    dest_host: Option<String>,
+    dest: String,
+    excludes: Vec<String>,
+    options: SendOptions,
+    /// The estimated size of the data sent.
+    sent: Option<usize>,
+}
+
+impl ZfsReplicate {
+    pub fn new(
+        src_host: Option<&str>,
+        src: &str,
+        dest_host: Option<&str>,
+        dest: &str,
+        excludes: &[String],
+        options: SendOptions,
+    ) -> Result<ZfsReplicate> {
+        Ok(ZfsReplicate {
+            src_host: src_host.map(|h| h.into()),
+            src: src.into(),
+            dest_host: dest_host.map(|h| h.into()),
+            dest: dest.into(),
+            excludes: excludes.to_vec(),
+            options,
+            sent: None,
+        })
+    }
+}
+
+impl Action for ZfsReplicate {
+    fn perform(&mut self) -> Result<()> {
+        info!("Zfs replicate {}", self.describe());
+        let src_zfs = Zfs::new(self.src_host.as_deref(), "")?;
+        let dest_zfs = Zfs::new(self.dest_host.as_deref(), "")?;
+        let excludes: Vec<_> = self.excludes.iter().map(|e| e.as_str()).collect();
+        let sent = src_zfs.clone(
+            &self.src,
+            &self.dest,
+            &dest_zfs,
+            true,
+            &excludes,
+            &self.options,
+        )?;
+        self.sent = Some(sent);
+        Ok(())
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        // No cleanup.
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Zfs replicate {}{} to {}{}{}",
+            self.src_host
+                .as_ref()
+                .map(|h| format!("{}:", h))
+                .unwrap_or_default(),
+            self.src,
+            self.dest_host
+                .as_ref()
+                .map(|h| format!("{}:", h))
+                .unwrap_or_default(),
+            self.dest,
+            if self.options.raw { " (raw)" } else { "" }
+        )
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        self.sent.map(|sent| Stats::ZfsSend(sent as u64))
+    }
+
+    // Each attempt resumes any partial receive, and then starts from the
+    // newest snapshot common to both sides, so a retry only sends what
+    // hasn't been received.
+    fn retryable(&self) -> bool {
+        true
+    }
+
+    // Show the clone plan, including any partially received filesystems
+    // that would be resumed.
+    fn pretend(&self) -> Result<()> {
+        println!("would: {}", self.describe());
+        let src_zfs = Zfs::new(self.src_host.as_deref(), "")?;
+        let dest_zfs = Zfs::new(self.dest_host.as_deref(), "")?;
+        let excludes: Vec<_> = self.excludes.iter().map(|e| e.as_str()).collect();
+        src_zfs.clone(
+            &self.src,
+            &self.dest,
+            &dest_zfs,
+            false,
+            &excludes,
+            &self.options,
+        )?;
+        Ok(())
+    }
+}
+
+/// An action that prunes the snapshots of a tree of ZFS filesystems,
+/// according to a retention policy.
+pub struct ZfsPrune {
+    host: Option<String>,
+    volume: String,
+    retention: Retention,
+    excludes: Vec<String>,
+}
+
+impl ZfsPrune {
+    pub fn new(
+        host: Option<&str>,
+        volume: &str,
+        retention: &Retention,
+        excludes: &[String],
+    ) -> Result<ZfsPrune> {
+        Ok(ZfsPrune {
+            host: host.map(|h| h.into()),
+            volume: volume.into(),
+            retention: retention.clone(),
+            excludes: excludes.to_vec(),
+        })
+    }
+
+    fn prune(&self, really: bool) -> Result<()> {
+        let zfs = Zfs::new(self.host.as_deref(), "")?;
+        let excludes: Vec<_> = self.excludes.iter().map(|e| e.as_str()).collect();
+        zfs.prune_tree(&self.volume, &self.retention, &excludes, really)
+    }
+}
+
+impl Action for ZfsPrune {
+    fn perform(&mut self) -> Result<()> {
+        info!("Zfs prune {}", self.describe());
+        self.prune(true)
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        // No cleanup.
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Zfs prune {}{}",
+            self.host
+                .as_ref()
+                .map(|h| format!("{}:", h))
+                .unwrap_or_default(),
+            self.volume
+        )
+    }
+
+    // Show which snapshots would be kept, and which pruned.
+    fn pretend(&self) -> Result<()> {
+        println!("would: {}", self.describe());
+        self.prune(false)
+    }
 }
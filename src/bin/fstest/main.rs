@@ -91,7 +91,7 @@ fn backup_lvm(lvm: &lvm::LvmTest, zfs: &zfs::ZfsTest) -> Result<()> {
         &format!("{}", local),
     )?));
 
-    run.run(false)?;
+    run.run(false).check()?;
 
     Ok(())
 }
@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Borg repository operations.
+//!
+//! Borg is always run through the wrapper script from the config file,
+//! which is responsible for setting up the repository location and
+//! passphrase.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Deserialize;
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{checked::CheckedExt, timestamp};
+
+pub struct Borg {
+    /// The borg script to run.
+    script: String,
+}
+
+/// An archive made by rdump of a particular volume.
+#[derive(Debug)]
+pub struct Archive {
+    pub name: String,
+    /// The time from the archive name.
+    pub time: DateTime<Utc>,
+}
+
+impl Borg {
+    pub fn new(script: &str) -> Borg {
+        Borg {
+            script: script.into(),
+        }
+    }
+
+    /// The script used to reach this repository.
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// Check the consistency of the repository and its archives.  With
+    /// `verify_data`, all of the data is read back and verified as well,
+    /// which can take a long time.
+    pub fn check(&self, verify_data: bool) -> Result<()> {
+        info!(
+            "Borg check{} via {}",
+            if verify_data { " (verify data)" } else { "" },
+            self.script
+        );
+        let mut cmd = Command::new(&self.script);
+        cmd.arg("check");
+        if verify_data {
+            cmd.arg("--verify-data");
+        }
+        cmd.checked_noio()?;
+        Ok(())
+    }
+
+    /// Free space in the repository from deleted archives.
+    pub fn compact(&self) -> Result<()> {
+        info!("Borg compact via {}", self.script);
+        Command::new(&self.script).arg("compact").checked_noio()?;
+        Ok(())
+    }
+
+    /// Return all of the archives of the given volume, oldest first.
+    pub fn archives(&self, volume: &str) -> Result<Vec<Archive>> {
+        let out = Command::new(&self.script)
+            .args([
+                "list",
+                "--json",
+                "--glob-archives",
+                &format!("{}-*", volume),
+            ])
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .checked_output()?;
+        let list: ListOutput = serde_json::from_slice(&out.stdout)
+            .map_err(|e| anyhow!("Unable to parse borg list output: {}", e))?;
+
+        // The glob will also match volumes whose names start with this
+        // one, such as `boot-efi` for `boot`, which are filtered out by
+        // requiring the rest of the name to be a timestamp.
+        let mut archives: Vec<_> = list
+            .archives
+            .into_iter()
+            .filter_map(|a| {
+                timestamp::parse_named(volume, &a.name).map(|time| Archive { name: a.name, time })
+            })
+            .collect();
+        archives.sort_by_key(|a| a.time);
+        Ok(archives)
+    }
+
+    /// Extract the given paths from an archive into `target`, removing
+    /// `strip` leading components from each path.  If `paths` is empty,
+    /// the whole archive is extracted.
+    pub fn extract(
+        &self,
+        archive: &str,
+        paths: &[&str],
+        strip: usize,
+        target: &Path,
+    ) -> Result<()> {
+        info!("Borg extract of {} into {:?}", archive, target);
+        // Borg extracts into its current directory, so a script given
+        // relative to ours has to be found before changing to the target.
+        let script = Path::new(&self.script);
+        let script = if script.is_relative() && script.components().count() > 1 {
+            script
+                .canonicalize()
+                .map_err(|e| anyhow!("Unable to find borg script {:?}: {}", self.script, e))?
+        } else {
+            script.to_path_buf()
+        };
+        let mut cmd = Command::new(script);
+        cmd.arg("extract");
+        if strip > 0 {
+            cmd.arg("--strip-components");
+            cmd.arg(strip.to_string());
+        }
+        cmd.arg(format!("::{}", archive));
+        cmd.args(paths);
+        cmd.current_dir(target);
+        cmd.checked_noio()?;
+        Ok(())
+    }
+}
+
+// The parts of the `borg list --json` output that we use.
+#[derive(Deserialize)]
+struct ListOutput {
+    archives: Vec<ListArchive>,
+}
+
+#[derive(Deserialize)]
+struct ListArchive {
+    name: String,
+}
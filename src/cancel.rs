@@ -0,0 +1,318 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Cancellation and timeouts.
+//!
+//! The runner performs each action within a `Scope`, which may have a
+//! deadline.  Commands run by actions are waited for with `wait`, which
+//! kills the command if the deadline passes, or if rdump receives SIGINT
+//! or SIGTERM.  Outside of a scope (such as during cleanups), commands are
+//! always allowed to finish, so that cleanups still happen after a
+//! cancellation.
+//!
+//! Once the signal handlers are installed, commands are started in their
+//! own process group, so that killing them also kills anything they start,
+//! such as the borg and ssh processes started by the borg script.  This is
+//! only done when rdump has no terminal, as when run by a timer or the
+//! daemon.  A command outside of the terminal's foreground group is
+//! stopped if it reads from the terminal, such as for an ssh host key,
+//! sudo password or borg passphrase prompt.  So with a terminal, or without
+//! the handlers, commands stay in rdump's group, where they can prompt,
+//! and a SIGINT from the terminal reaches them along with rdump.  A timeout
+//! then only kills the command itself.
+//!
+//! Actions that do their work within rdump (such as the rsure scans) can't
+//! be interrupted this way, so they fail once they finish instead.  A
+//! second SIGINT or SIGTERM will exit immediately, without any cleanups.
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    flag,
+};
+use std::{
+    fs::OpenOptions,
+    io,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref CANCELLED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    static ref SCOPE: Mutex<Option<Limit>> = Mutex::new(None);
+    // Does rdump have a controlling terminal?
+    static ref TERMINAL: bool = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_ok();
+}
+
+/// Have the signal handlers been installed?
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The limits of the active scope.
+#[derive(Clone, Copy)]
+struct Limit {
+    deadline: Option<Instant>,
+}
+
+/// How often to check on running commands.
+const POLL: Duration = Duration::from_millis(100);
+
+/// How long to give a command to exit after SIGTERM before killing it.
+const GRACE: Duration = Duration::from_secs(10);
+
+/// Install handlers for SIGINT and SIGTERM that request cancellation.
+pub fn install() -> Result<()> {
+    for &sig in &[SIGINT, SIGTERM] {
+        // If the flag is already set, this is the second signal, so exit.
+        flag::register_conditional_shutdown(sig, 1, Arc::clone(&CANCELLED))?;
+        flag::register(sig, Arc::clone(&CANCELLED))?;
+    }
+    INSTALLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Has a cancellation been requested?
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// While a `Scope` is alive, commands waited for with `wait` will be
+/// killed upon cancellation, or when the deadline passes.
+pub struct Scope(());
+
+impl Scope {
+    pub fn new(timeout: Option<Duration>) -> Scope {
+        let limit = Limit {
+            deadline: timeout.map(|t| Instant::now() + t),
+        };
+        *SCOPE.lock().unwrap() = Some(limit);
+        Scope(())
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        *SCOPE.lock().unwrap() = None;
+    }
+}
+
+/// Check if the active scope should stop.  Returns an error describing
+/// why, if so.
+pub fn check() -> Result<()> {
+    let limit = match *SCOPE.lock().unwrap() {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    if cancelled() {
+        return Err(anyhow!("Cancelled"));
+    }
+    if let Some(deadline) = limit.deadline {
+        if Instant::now() >= deadline {
+            return Err(anyhow!("Timed out"));
+        }
+    }
+    Ok(())
+}
+
+/// Spawn a command, in its own process group if the signal handlers are
+/// installed, and there is no terminal it could prompt on.
+pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+    if INSTALLED.load(Ordering::SeqCst) && !*TERMINAL {
+        cmd.process_group(0);
+    }
+    cmd.spawn()
+}
+
+/// Wait for a child started with `spawn`.  If the active scope is
+/// cancelled or times out, the child's process group is killed, and an
+/// error is returned.
+pub fn wait(child: &mut Child) -> Result<ExitStatus> {
+    let mut statuses = wait_all(&mut [child])?;
+    Ok(statuses.remove(0))
+}
+
+/// Wait for all of the children of a pipeline, started with `spawn`.  If
+/// the active scope is cancelled or times out, all of the children are
+/// killed.
+pub fn wait_all(children: &mut [&mut Child]) -> Result<Vec<ExitStatus>> {
+    let mut statuses = vec![None; children.len()];
+    loop {
+        for (child, status) in children.iter_mut().zip(statuses.iter_mut()) {
+            if status.is_none() {
+                *status = child.try_wait()?;
+            }
+        }
+        if statuses.iter().all(|s| s.is_some()) {
+            return Ok(statuses.into_iter().map(|s| s.unwrap()).collect());
+        }
+        if let Err(err) = check() {
+            let mut running: Vec<_> = children
+                .iter_mut()
+                .zip(statuses.iter())
+                .filter(|(_, status)| status.is_none())
+                .map(|(child, _)| &mut **child)
+                .collect();
+            for child in &running {
+                warn!("{}, stopping process {}", err, child.id());
+            }
+            kill(&mut running)?;
+            return Err(err);
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Kill the children, along with their process groups if they have their
+/// own, first asking nicely.  All of them are signalled and waited for,
+/// even if some fail, and then the first error is returned.
+pub fn kill(children: &mut [&mut Child]) -> Result<()> {
+    let mut first = None;
+    let mut record = |result: io::Result<()>| {
+        if let Err(err) = result {
+            first.get_or_insert(err);
+        }
+    };
+
+    let mut done = vec![false; children.len()];
+    signal(children, &done, libc::SIGTERM);
+    let start = Instant::now();
+    while start.elapsed() < GRACE && done.iter().any(|d| !d) {
+        for (child, done) in children.iter_mut().zip(done.iter_mut()) {
+            if !*done {
+                match child.try_wait() {
+                    Ok(status) => *done = status.is_some(),
+                    Err(err) => {
+                        *done = true;
+                        record(Err(err));
+                    }
+                }
+            }
+        }
+        thread::sleep(POLL);
+    }
+
+    // Those already reaped aren't signalled, as their pids may be reused.
+    signal(children, &done, libc::SIGKILL);
+    for (child, done) in children.iter_mut().zip(done.iter()) {
+        if !*done {
+            record(child.wait().map(|_| ()));
+        }
+    }
+
+    match first {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+/// Tests that run commands hold this, as they share the scope, and the
+/// children of the process.
+#[cfg(test)]
+pub(crate) fn serial() -> std::sync::MutexGuard<'static, ()> {
+    static SERIAL: Mutex<()> = Mutex::new(());
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Request cancellation, as a SIGINT would, or clear the request.  Tests
+/// that use this must hold `serial`, and clear it when done.
+#[cfg(test)]
+pub(crate) fn set_cancelled(value: bool) {
+    CANCELLED.store(value, Ordering::SeqCst);
+}
+
+/// Send a signal to each of the children that isn't done, or to its
+/// process group if it has its own.
+fn signal(children: &[&mut Child], done: &[bool], sig: libc::c_int) {
+    for (child, _) in children.iter().zip(done).filter(|(_, &done)| !done) {
+        let pid = child.id() as libc::pid_t;
+        let target = if unsafe { libc::getpgid(pid) } == pid {
+            -pid
+        } else {
+            pid
+        };
+        unsafe {
+            libc::kill(target, sig);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleep(secs: &str) -> Child {
+        spawn(Command::new("sleep").arg(secs)).unwrap()
+    }
+
+    #[test]
+    fn same_group() {
+        let _serial = serial();
+        // The handlers aren't installed by the tests, so commands stay in
+        // our process group.
+        let mut child = sleep("0");
+        let group = unsafe { libc::getpgid(child.id() as libc::pid_t) };
+        assert_eq!(group, unsafe { libc::getpgid(0) });
+        assert!(wait(&mut child).unwrap().success());
+    }
+
+    #[test]
+    fn no_scope() {
+        let _serial = serial();
+        // Without a scope, commands finish, whatever the timeout was.
+        drop(Scope::new(Some(Duration::from_millis(1))));
+        thread::sleep(Duration::from_millis(10));
+        let mut child = spawn(Command::new("sh").args(&["-c", "sleep 0.2; exit 3"])).unwrap();
+        assert_eq!(wait(&mut child).unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn timeout() {
+        let _serial = serial();
+        let _scope = Scope::new(Some(Duration::from_millis(200)));
+        let start = Instant::now();
+        let mut child = sleep("30");
+        let err = wait(&mut child).unwrap_err();
+        assert_eq!(err.to_string(), "Timed out");
+        // Sleep exits on SIGTERM, without waiting for the grace period.
+        assert!(start.elapsed() < GRACE);
+        // The child was waited for, so there is nothing left to reap.
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn timeout_all() {
+        let _serial = serial();
+        let _scope = Scope::new(Some(Duration::from_millis(200)));
+        let mut done = sleep("0");
+        let mut slow = sleep("30");
+        let mut slower = sleep("60");
+        let start = Instant::now();
+        let err = wait_all(&mut [&mut done, &mut slow, &mut slower]).unwrap_err();
+        assert_eq!(err.to_string(), "Timed out");
+        assert!(start.elapsed() < GRACE);
+        for child in &mut [slow, slower] {
+            assert!(child.try_wait().unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn within_time() {
+        let _serial = serial();
+        let _scope = Scope::new(Some(Duration::from_secs(30)));
+        let mut first = sleep("0");
+        let mut second = spawn(&mut Command::new("false")).unwrap();
+        let statuses = wait_all(&mut [&mut first, &mut second]).unwrap();
+        assert!(statuses[0].success());
+        assert!(!statuses[1].success());
+        assert!(check().is_ok());
+    }
+}
@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Catalog of backups.
+//!
+//! Each run of rdump makes a borg archive of a volume, and a snapshot of
+//! its ZFS mirror, both named with the same timestamp.  The catalog
+//! gathers these together, so that it is easy to see what backups exist
+//! of each volume.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    borg::{Archive, Borg},
+    config::Volume,
+    period::Period,
+    timestamp,
+    zfs::Zfs,
+};
+
+/// The backups of a single volume.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub name: String,
+    /// The backups, oldest first.
+    pub backups: Vec<Backup>,
+    /// The time of the most recent backup.
+    pub newest: Option<DateTime<Utc>>,
+    /// Is the most recent backup older than the threshold?
+    pub stale: bool,
+}
+
+/// A single backup, which may exist in borg, in ZFS, or both.
+#[derive(Debug, Serialize)]
+pub struct Backup {
+    pub time: DateTime<Utc>,
+    /// The name of the borg archive.
+    pub borg: Option<String>,
+    /// The name of the ZFS snapshot of the mirror.
+    pub zfs: Option<String>,
+}
+
+/// Build a catalog of the backups of the given volumes.  Volumes whose
+/// most recent backup is older than `max_age` are marked as stale.
+pub fn catalog(borg: &Borg, volumes: &[Volume], max_age: Period) -> Result<Vec<Entry>> {
+    let zfs = if volumes.iter().any(|v| v.mirror.is_some()) {
+        Some(Zfs::new(None, "")?)
+    } else {
+        None
+    };
+    let now = Utc::now();
+
+    let mut result = vec![];
+    for vol in volumes {
+        let archives = borg.archives(vol.name)?;
+        let mirror = match (&zfs, vol.mirror) {
+            (Some(zfs), Some(mirror)) => zfs
+                .filesystems
+                .iter()
+                .find(|fs| fs.name == mirror)
+                .map(|fs| (mirror, &fs.snaps[..])),
+            _ => None,
+        };
+        result.push(merge(vol.name, archives, mirror, max_age, now));
+    }
+
+    Ok(result)
+}
+
+/// Merge the borg `archives` of a volume (oldest first) with the snapshots
+/// of its ZFS `mirror`, if it has one, by their timestamps.  Should a
+/// retried backup leave more than one archive, the last is shown.
+fn merge(
+    name: &str,
+    archives: Vec<Archive>,
+    mirror: Option<(&str, &[String])>,
+    max_age: Period,
+    now: DateTime<Utc>,
+) -> Entry {
+    let mut backups = BTreeMap::new();
+
+    for archive in archives {
+        let time = archive.time;
+        backups
+            .entry(time)
+            .or_insert_with(|| Backup::new(time))
+            .borg = Some(archive.name);
+    }
+
+    if let Some((mirror, snaps)) = mirror {
+        for snap in snaps {
+            if let Some(time) = timestamp::parse(snap) {
+                backups.entry(time).or_insert_with(|| Backup::new(time)).zfs =
+                    Some(format!("{}@{}", mirror, snap));
+            }
+        }
+    }
+
+    let newest = backups.keys().next_back().cloned();
+    let stale = match newest {
+        Some(newest) => now - newest > max_age.duration(),
+        None => true,
+    };
+    Entry {
+        name: name.to_string(),
+        backups: backups.into_values().collect(),
+        newest,
+        stale,
+    }
+}
+
+/// Print the catalog as a table.
+pub fn show(catalog: &[Entry]) {
+    for entry in catalog {
+        let newest = match entry.newest {
+            Some(t) => format!("newest {}", t.format("%Y-%m-%d %H:%M:%S UTC")),
+            None => "no backups".to_string(),
+        };
+        println!(
+            "{} ({}){}",
+            entry.name,
+            newest,
+            if entry.stale { "  ** STALE **" } else { "" }
+        );
+        for backup in &entry.backups {
+            println!(
+                "    {}  {:<4}  {:<3}",
+                backup.time.format(timestamp::FORMAT),
+                if backup.borg.is_some() { "borg" } else { "" },
+                if backup.zfs.is_some() { "zfs" } else { "" }
+            );
+        }
+    }
+}
+
+impl Backup {
+    fn new(time: DateTime<Utc>) -> Backup {
+        Backup {
+            time,
+            borg: None,
+            zfs: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(text: &str) -> DateTime<Utc> {
+        timestamp::parse(text).unwrap()
+    }
+
+    /// The archives of `home` with the given names, as borg lists them.
+    fn archives(names: &[&str]) -> Vec<Archive> {
+        let mut archives: Vec<_> = names
+            .iter()
+            .map(|n| Archive {
+                name: n.to_string(),
+                time: timestamp::parse_named("home", n).unwrap(),
+            })
+            .collect();
+        archives.sort_by_key(|a| a.time);
+        archives
+    }
+
+    /// The backups of the entry, as `time borg zfs`, with `-` for those
+    /// missing.
+    fn backups(entry: &Entry) -> Vec<String> {
+        entry
+            .backups
+            .iter()
+            .map(|b| {
+                format!(
+                    "{} {} {}",
+                    b.time.format(timestamp::FORMAT),
+                    b.borg.as_deref().unwrap_or("-"),
+                    b.zfs.as_deref().unwrap_or("-")
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merged() {
+        let archives = archives(&[
+            "home-20210603T020000.2",
+            "home-20210601T020000",
+            "home-20210602T020000",
+        ]);
+        let snaps: Vec<String> = [
+            "20210602T020000",
+            "20210604T020000",
+            "manual",
+            "20210601T020000",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let entry = merge(
+            "home",
+            archives,
+            Some(("tank/home", &snaps)),
+            "2d".parse().unwrap(),
+            time("20210605T000000"),
+        );
+        assert_eq!(entry.name, "home");
+        assert_eq!(
+            backups(&entry),
+            vec![
+                "20210601T020000 home-20210601T020000 tank/home@20210601T020000",
+                "20210602T020000 home-20210602T020000 tank/home@20210602T020000",
+                "20210603T020000 home-20210603T020000.2 -",
+                "20210604T020000 - tank/home@20210604T020000",
+            ]
+        );
+        assert_eq!(entry.newest, Some(time("20210604T020000")));
+        assert!(!entry.stale);
+    }
+
+    #[test]
+    fn retried() {
+        // The last attempt's archive is shown.
+        let entry = merge(
+            "home",
+            archives(&["home-20210601T020000", "home-20210601T020000.2"]),
+            None,
+            "2d".parse().unwrap(),
+            time("20210601T030000"),
+        );
+        assert_eq!(
+            backups(&entry),
+            vec!["20210601T020000 home-20210601T020000.2 -"]
+        );
+    }
+
+    #[test]
+    fn stale() {
+        let max_age = "1d".parse().unwrap();
+        let now = time("20210603T020000");
+        let entry = merge(
+            "home",
+            archives(&["home-20210602T020000"]),
+            None,
+            max_age,
+            now,
+        );
+        assert!(!entry.stale);
+        let entry = merge(
+            "home",
+            archives(&["home-20210602T015959"]),
+            None,
+            max_age,
+            now,
+        );
+        assert!(entry.stale);
+
+        let entry = merge("home", vec![], None, max_age, now);
+        assert!(entry.backups.is_empty());
+        assert_eq!(entry.newest, None);
+        assert!(entry.stale);
+    }
+}
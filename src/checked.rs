@@ -1,16 +1,27 @@
 // SPDX-License-Identifier: Apache-2.0
 //! An extension to Command to allow checked runs.
+//!
+//! The commands are run with `cancel`, so that they are stopped when the
+//! running action is cancelled or times out.
 
 use anyhow::{anyhow, Result};
-use std::process::{Command, Output, Stdio};
+use std::{
+    io::{self, Read},
+    process::{Command, Output, Stdio},
+    thread::{self, JoinHandle},
+};
+
+use crate::cancel;
 
 pub trait CheckedExt {
     /// Run the given command, normalizing to the local Result type, and returning a local error if
     /// the command doesn't return success.
     fn checked_run(&mut self) -> Result<()>;
 
-    /// Run command, collecting all of its output.  Runs Command's `output` method, with an
-    /// additional check of the status result.
+    /// Run command, collecting its stdout, and an additional check of the status result.  Unlike
+    /// Command's `output` method, stdin and stderr are left as the caller sets them, and so are
+    /// inherited if not set.  If stderr is set to piped, it is collected, and included in the
+    /// error if the command fails.
     fn checked_output(&mut self) -> Result<Output>;
 
     /// Run a command, returning an error if the command doesn't return
@@ -21,7 +32,8 @@ pub trait CheckedExt {
 
 impl CheckedExt for Command {
     fn checked_run(&mut self) -> Result<()> {
-        let status = self.status()?;
+        let mut child = cancel::spawn(self)?;
+        let status = cancel::wait(&mut child)?;
         if !status.success() {
             return Err(anyhow!("Error running command: {:?} ({:?})", self, status));
         }
@@ -29,12 +41,27 @@ impl CheckedExt for Command {
     }
 
     fn checked_output(&mut self) -> Result<Output> {
-        let out = self.output()?;
+        // The output is read in separate threads, so that the command
+        // can't block on a full pipe while we wait for it.
+        self.stdout(Stdio::piped());
+        let mut child = cancel::spawn(self)?;
+        let stdout = reader(child.stdout.take());
+        let stderr = reader(child.stderr.take());
+        let status = cancel::wait(&mut child)?;
+        let out = Output {
+            status,
+            stdout: stdout.join().expect("Output reader")?,
+            stderr: stderr.join().expect("Output reader")?,
+        };
         if !out.status.success() {
+            let message = String::from_utf8_lossy(&out.stderr);
+            let message = message.trim();
             return Err(anyhow!(
-                "Error running command: {:?} ({:?})",
+                "Error running command: {:?} ({:?}){}{}",
                 self,
-                out.status
+                out.status,
+                if message.is_empty() { "" } else { ": " },
+                message
             ));
         }
         Ok(out)
@@ -47,3 +74,96 @@ impl CheckedExt for Command {
         Ok(())
     }
 }
+
+/// Read all of a pipe from a child in a separate thread.  A missing pipe
+/// reads as empty.
+fn reader<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process, time::Duration};
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(&["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn run() {
+        let _serial = cancel::serial();
+        sh("exit 0").checked_run().unwrap();
+        let err = sh("exit 3").checked_run().unwrap_err();
+        assert!(
+            err.to_string().starts_with("Error running command: "),
+            "{}",
+            err
+        );
+        sh("exit 0").checked_noio().unwrap();
+        assert!(sh("exit 1").checked_noio().is_err());
+    }
+
+    #[test]
+    fn output() {
+        let _serial = cancel::serial();
+        let out = sh("echo out; echo err >&2").checked_output().unwrap();
+        assert_eq!(out.stdout, b"out\n");
+        // Stderr is inherited unless it is piped.
+        assert!(out.stderr.is_empty());
+        let out = sh("echo out; echo err >&2")
+            .stderr(Stdio::piped())
+            .checked_output()
+            .unwrap();
+        assert_eq!(out.stdout, b"out\n");
+        assert_eq!(out.stderr, b"err\n");
+    }
+
+    #[test]
+    fn output_error() {
+        let _serial = cancel::serial();
+        let err = sh("echo out; echo '  it failed' >&2; exit 2")
+            .stderr(Stdio::piped())
+            .checked_output()
+            .unwrap_err();
+        // Only stderr is included, trimmed.
+        assert!(err.to_string().ends_with(")): it failed"), "{}", err);
+        let err = sh("exit 2").checked_output().unwrap_err();
+        assert!(err.to_string().ends_with("))"), "{}", err);
+    }
+
+    #[test]
+    fn output_stdin() {
+        let _serial = cancel::serial();
+        // Stdin is left to the caller, rather than always null.
+        let out = sh("cat").stdin(Stdio::null()).checked_output().unwrap();
+        assert!(out.stdout.is_empty());
+
+        let path = env::temp_dir().join(format!("rdump-test-{}-stdin", process::id()));
+        fs::write(&path, "input").unwrap();
+        let out = sh("cat")
+            .stdin(fs::File::open(&path).unwrap())
+            .checked_output();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(out.unwrap().stdout, b"input");
+    }
+
+    #[test]
+    fn output_timeout() {
+        let _serial = cancel::serial();
+        let _scope = cancel::Scope::new(Some(Duration::from_millis(200)));
+        // The output is still being read when the command is stopped.
+        let err = sh("echo partial; exec sleep 30")
+            .checked_output()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Timed out");
+    }
+}
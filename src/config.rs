@@ -1,42 +1,139 @@
 // SPDX-License-Identifier: Apache-2.0
 //! Configuration.
 
-use anyhow::Result;
-use chrono::Utc;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
-use std::{
-    collections::{BTreeMap, HashSet},
-    fs::File,
-    path::Path,
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+use crate::{
+    actions::{self, Policy, Retry, Runner},
+    borg::Borg,
+    catalog,
+    freshness::{self, AgeLimits, Status},
+    history::{self, History},
+    lock::{self, Lock},
+    maintain::{self, Schedule},
+    metrics::MetricsWriter,
+    notify::{self, Notifier},
+    period::Period,
+    restore,
+    retention::Retention,
+    schedule, state, systemd, timestamp,
+    zfs::{Compression, SendOptions},
 };
 
-use crate::actions::{self, Runner};
+pub use self::check::Problem;
+
+use self::select::NameFilter;
+
+mod check;
+mod select;
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigFile {
     config: Config,
     simple: Vec<Simple>,
     lvm: Vec<Lvm>,
+    maintain: Option<Maintain>,
+    #[serde(default)]
+    freshness: AgeLimits,
+    notify: Option<Notify>,
+    // ZFS replications, each a map from a name to the replication.
+    #[serde(default)]
+    zfs: Vec<BTreeMap<String, Replicate>>,
+    // Named profiles, selected with `backup --profile`.
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    // Settings for the units made by `rdump systemd generate`.
+    #[serde(default)]
+    systemd: systemd::Service,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     borg: String,
+    // Where to keep state between runs.  Defaults to `state::DEFAULT_STATE`.
+    state: Option<String>,
+    // Where to record the history of runs.  Defaults to
+    // `history::DEFAULT_HISTORY`.
+    history: Option<String>,
+    // A directory to write prometheus metrics to, for the node_exporter
+    // textfile collector.
+    metrics: Option<String>,
+    // The lock held during each run.  Defaults to `lock::DEFAULT_LOCK`.
+    lock: Option<String>,
+    // How long a scheduled run waits for another run to finish before
+    // failing.  Waits as long as needed if not given.
+    lock_wait: Option<Period>,
+    // Default timeouts for the actions of every volume.
+    #[serde(default)]
+    timeouts: Timeouts,
+    // How to retry the actions that can fail for transient reasons.
+    #[serde(default)]
+    retries: Retries,
+}
+
+// How long each kind of action may take before it is killed and fails.
+// Actions without a timeout may take as long as they need.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    snapshot: Option<Period>,
+    mount: Option<Period>,
+    rsure: Option<Period>,
+    borg: Option<Period>,
+    rsync: Option<Period>,
+    zfs_snapshot: Option<Period>,
+    replicate: Option<Period>,
+    prune: Option<Period>,
+}
+
+// Retry policies for the kinds of actions that can be retried.  Those
+// without a policy are attempted only once.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retries {
+    borg: Option<Retry>,
+    replicate: Option<Retry>,
+}
+
+// Repository maintenance schedule.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Maintain {
+    // How often to run `borg check`.  Runs every time if not given.
+    check: Option<Period>,
+    // How often to also verify the data.  Never done if not given.
+    verify_data: Option<Period>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Simple {
     name: String,
     mount: String,
+    // Tags, for selecting groups of volumes as `@tag`.
+    #[serde(default)]
+    tags: Vec<String>,
     actions: Actions,
     // A possible ZFS filesystem to rsync mirror to.
     zfs: Option<Zfs>,
+    // Timeouts for this volume, overriding those in the config.
+    #[serde(default)]
+    timeouts: Timeouts,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Lvm {
     name: String,
     mount: String,
+    // Tags, for selecting groups of volumes as `@tag`.
+    #[serde(default)]
+    tags: Vec<String>,
     snap: String,
     vg: String,
     lv: String,
@@ -45,6 +142,9 @@ pub struct Lvm {
     actions: Actions,
     // A possible ZFS filesystem to rsync mirror to.
     zfs: Option<Zfs>,
+    // Timeouts for this volume, overriding those in the config.
+    #[serde(default)]
+    timeouts: Timeouts,
 }
 
 // These phases provide a convenient way to group all of a given phase
@@ -58,15 +158,120 @@ enum Phase {
     Borg,
     Rsync,
     ZfsSnapshot,
+    Prune,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Actions(Vec<String>);
 
+// A selection of volumes and actions, so that several schedules can back
+// up different things, in different ways, from the same config.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    // Volumes with any of these tags are backed up.  All volumes are if
+    // no tags are given.
+    #[serde(default)]
+    tags: Vec<String>,
+    // The actions to perform.  All but prune are run if not given.
+    actions: Option<Vec<Step>>,
+    // When to run this profile, by systemd timer or `rdump daemon`.
+    schedule: Option<schedule::Schedule>,
+    // A random delay added to each scheduled run.
+    randomized_delay: Option<Period>,
+}
+
+// The actions that a profile can select.  LVM volumes are always
+// snapshotted when any of the actions that read the snapshot are
+// performed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Snap,
+    Rsure,
+    Borg,
+    Rsync,
+    ZfsSnapshot,
+    Prune,
+}
+
+// The steps to perform in a run.
+struct Steps<'a>(Option<&'a [Step]>);
+
+/// The parts of a configured volume that are common to all kinds of
+/// volumes.
+pub struct Volume<'a> {
+    pub name: &'a str,
+    /// Where the volume is normally mounted.
+    pub mount: &'a str,
+    /// The directory that is backed up.  For LVM volumes, this is where
+    /// the snapshot is mounted.
+    pub source: &'a str,
+    /// The ZFS filesystem this volume is mirrored to.
+    pub mirror: Option<&'a str>,
+    pub tags: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Zfs {
     volume: String,
     mount: String,
+    // Which snapshots of the volume to keep when pruning.  None are
+    // pruned if not given.
+    retention: Option<Retention>,
+}
+
+// How to notify about the outcome of runs.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Notify {
+    mail: Option<notify::Mail>,
+    webhook: Option<notify::Webhook>,
+}
+
+// Replication of a tree of ZFS filesystems, along with their snapshots.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replicate {
+    src: Endpoint,
+    // Tags, for selecting groups of replications as `@tag`.
+    #[serde(default)]
+    tags: Vec<String>,
+    dest: Endpoint,
+    // Regexes of source filesystems not to replicate.
+    #[serde(default)]
+    excludes: Vec<String>,
+    // Send encrypted filesystems raw, so they are never decrypted on the
+    // destination.
+    #[serde(default)]
+    raw: bool,
+    // Keep blocks compressed as they are on disk, allow large blocks, and
+    // send embedded blocks as they are (`zfs send -c`, `-L`, and `-e`).
+    #[serde(default)]
+    compressed: bool,
+    #[serde(default)]
+    large_blocks: bool,
+    #[serde(default)]
+    embedded: bool,
+    // Buffer the stream with mbuffer, of this size, such as `1G`.
+    buffer: Option<String>,
+    // Compress the stream when it crosses ssh: `zstd` or `lz4`.
+    compress: Option<Compression>,
+    // Timeouts for this replication, overriding those in the config.
+    #[serde(default)]
+    timeouts: Timeouts,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    // The host, reached with ssh.  Local if not given.
+    host: Option<String>,
+    volume: String,
+    // Which snapshots of the filesystems to keep when pruning.  None are
+    // pruned if not given.
+    retention: Option<Retention>,
 }
 
 impl ConfigFile {
@@ -74,8 +279,24 @@ impl ConfigFile {
         Ok(serde_yaml::from_reader(File::open(path)?)?)
     }
 
-    pub fn build_runner(&self, names: &[&str]) -> Result<Runner> {
-        let names = NameFilter::new(names);
+    /// Build a runner to back up the selected volumes, or all volumes if
+    /// none are selected.  See `select` for how volumes are selected.  A
+    /// profile further limits the volumes, and what is done to them.
+    pub fn build_runner(&self, names: &[&str], profile: Option<&str>) -> Result<Runner> {
+        let names = self.select(names)?;
+        let (tags, steps) = match profile {
+            Some(name) => {
+                let profile = self
+                    .profiles
+                    .get(name)
+                    .ok_or_else(|| anyhow!("No profile named {:?} in config", name))?;
+                let tags: Vec<_> = profile.tags.iter().map(|t| format!("@{}", t)).collect();
+                (tags, Steps(profile.actions.as_deref()))
+            }
+            None => (vec![], Steps(None)),
+        };
+        let tags: Vec<_> = tags.iter().map(|t| t.as_str()).collect();
+        let tags = self.select(&tags)?;
 
         let mut runners = BTreeMap::new();
         Self::add_runner(&mut runners, Phase::Timestamp, "Timestamps")?;
@@ -85,21 +306,32 @@ impl ConfigFile {
         Self::add_runner(&mut runners, Phase::Borg, "Borg")?;
         Self::add_runner(&mut runners, Phase::Rsync, "Rsync")?;
         Self::add_runner(&mut runners, Phase::ZfsSnapshot, "ZfsSnapshot")?;
+        Self::add_runner(&mut runners, Phase::Prune, "Prune")?;
 
+        let mut count = 0;
         for simp in &self.simple {
-            if !names.contains(&simp.name) {
-                break;
+            if !names.contains(&simp.name, &simp.tags) || !tags.contains(&simp.name, &simp.tags) {
+                continue;
             }
 
-            simp.add_actions(&mut runners, self)?;
+            simp.add_actions(&mut runners, self, &steps)?;
+            count += 1;
         }
 
         for lvm in &self.lvm {
-            if !names.contains(&lvm.name) {
-                break;
+            if !names.contains(&lvm.name, &lvm.tags) || !tags.contains(&lvm.name, &lvm.tags) {
+                continue;
             }
 
-            lvm.add_actions(&mut runners, self)?;
+            lvm.add_actions(&mut runners, self, &steps)?;
+            count += 1;
+        }
+
+        if let (0, Some(profile)) = (count, profile) {
+            return Err(anyhow!(
+                "No volumes selected by both the names and profile {:?}",
+                profile
+            ));
         }
 
         let mut runner = Runner::new()?;
@@ -109,10 +341,296 @@ impl ConfigFile {
         }
 
         runner.push(Box::new(actions::Message::new("Finished, cleaning up")?));
+        self.add_observers(&mut runner);
+
+        Ok(runner)
+    }
+
+    /// Perform maintenance on the borg repository.
+    pub fn maintain(&self, force: bool, pretend: bool) -> Result<()> {
+        let schedule = match self.maintain {
+            Some(ref m) => Schedule {
+                check: m.check,
+                verify_data: m.verify_data,
+            },
+            None => Schedule {
+                check: None,
+                verify_data: None,
+            },
+        };
+        let repos = vec![Borg::new(&self.config.borg)];
+        maintain::maintain(&repos, &schedule, self.state_path(), force, pretend)
+    }
+
+    /// Restore the named volume from borg into `target`.
+    pub fn restore(
+        &self,
+        name: &str,
+        at: Option<DateTime<Utc>>,
+        path: Option<&str>,
+        target: &Path,
+        pretend: bool,
+    ) -> Result<()> {
+        let volume = self.volume(name)?;
+        let borg = Borg::new(&self.config.borg);
+        restore::restore(&borg, &volume, at, path, target, pretend)
+    }
+
+    /// Build a catalog of the existing backups of the selected volumes, or
+    /// all volumes if none are selected.
+    pub fn catalog(&self, names: &[&str], max_age: Period) -> Result<Vec<catalog::Entry>> {
+        let names = self.select(names)?;
+        let volumes: Vec<_> = self
+            .volumes()
+            .into_iter()
+            .filter(|v| names.contains(v.name, v.tags))
+            .collect();
+        let borg = Borg::new(&self.config.borg);
+        catalog::catalog(&borg, &volumes, max_age)
+    }
+
+    /// Check that the backups of all volumes are recent enough.
+    pub fn check_freshness(&self) -> Result<Status> {
+        let history = self.history().load()?;
+        freshness::check(&self.volumes(), &self.freshness, &history)
+    }
+
+    /// Return all of the configured volumes.
+    pub fn volumes(&self) -> Vec<Volume<'_>> {
+        let simple = self.simple.iter().map(|s| Volume {
+            name: &s.name,
+            mount: &s.mount,
+            source: &s.mount,
+            mirror: s.zfs.as_ref().map(|z| z.volume.as_str()),
+            tags: &s.tags,
+        });
+        let lvm = self.lvm.iter().map(|l| Volume {
+            name: &l.name,
+            mount: &l.mount,
+            source: &l.snap,
+            mirror: l.zfs.as_ref().map(|z| z.volume.as_str()),
+            tags: &l.tags,
+        });
+        simple.chain(lvm).collect()
+    }
+
+    /// Build a filter for the given selection of volumes, checking that
+    /// each selects something.
+    fn select(&self, names: &[&str]) -> Result<NameFilter> {
+        let names = NameFilter::new(names)?;
+        let volumes: Vec<_> = self.volumes().iter().map(|v| (v.name, v.tags)).collect();
+        names.check(&volumes, "volume")?;
+        Ok(names)
+    }
+
+    /// Return the configured volume of the given name.
+    pub fn volume(&self, name: &str) -> Result<Volume<'_>> {
+        self.volumes()
+            .into_iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| anyhow!("No volume named {:?} in config", name))
+    }
+
+    /// The history of runs.
+    pub fn history(&self) -> History {
+        History::new(
+            self.config
+                .history
+                .as_deref()
+                .unwrap_or(history::DEFAULT_HISTORY),
+        )
+    }
+
+    /// The path of the state file.
+    pub fn state_path(&self) -> &str {
+        self.config.state.as_deref().unwrap_or(state::DEFAULT_STATE)
+    }
+
+    /// The path of the lock held during runs.
+    pub fn lock_path(&self) -> &str {
+        self.config.lock.as_deref().unwrap_or(lock::DEFAULT_LOCK)
+    }
+
+    /// Take the run lock.  With `wait`, as for scheduled runs, waits up
+    /// to `lock_wait` for another run to finish, rather than failing.
+    pub fn lock(&self, wait: bool) -> Result<Lock> {
+        if wait {
+            let timeout = self
+                .config
+                .lock_wait
+                .and_then(|t| t.duration().to_std().ok());
+            Lock::wait(self.lock_path(), timeout)
+        } else {
+            Lock::acquire(self.lock_path())
+        }
+    }
+
+    /// Build a runner to perform the selected ZFS replications, or all of
+    /// them if none are selected.
+    pub fn build_clone_runner(&self, names: &[&str]) -> Result<Runner> {
+        let names = NameFilter::new(names)?;
+        let reps: Vec<_> = self
+            .replications()
+            .map(|(name, rep)| (name, rep.tags.as_slice()))
+            .collect();
+        names.check(&reps, "zfs replication")?;
+
+        let mut runner = Runner::new()?;
+        runner.push(Box::new(actions::Message::new("Replicate")?));
+        for (name, rep) in self.replications() {
+            if !names.contains(name, &rep.tags) {
+                continue;
+            }
+            let action = actions::ZfsReplicate::new(
+                rep.src.host.as_deref(),
+                &rep.src.volume,
+                rep.dest.host.as_deref(),
+                &rep.dest.volume,
+                &rep.excludes,
+                SendOptions {
+                    raw: rep.raw,
+                    compressed: rep.compressed,
+                    large_blocks: rep.large_blocks,
+                    embedded: rep.embedded,
+                    buffer: rep.buffer.clone(),
+                    compress: rep.compress,
+                },
+            )?;
+            let timeouts = rep.timeouts.over(&self.config.timeouts);
+            let policy = Policy {
+                timeout: timeouts.replicate,
+                retry: self.config.retries.replicate,
+            };
+            runner.push_with(name, policy, Box::new(action));
+        }
+        self.add_observers(&mut runner);
+        Ok(runner)
+    }
 
+    /// Build a runner to prune the snapshots of the selected volumes and
+    /// zfs replications, or all of them if none are selected, according
+    /// to their retention policies.
+    pub fn build_prune_runner(&self, names: &[&str]) -> Result<Runner> {
+        let names = NameFilter::new(names)?;
+        let mut all: Vec<_> = self.volumes().iter().map(|v| (v.name, v.tags)).collect();
+        all.extend(
+            self.replications()
+                .map(|(name, rep)| (name, rep.tags.as_slice())),
+        );
+        names.check(&all, "volume or zfs replication")?;
+
+        let mut runner = Runner::new()?;
+        runner.push(Box::new(actions::Message::new("Prune")?));
+        let volumes = self
+            .simple
+            .iter()
+            .map(|s| (&s.name, &s.tags, &s.zfs, &s.timeouts))
+            .chain(
+                self.lvm
+                    .iter()
+                    .map(|l| (&l.name, &l.tags, &l.zfs, &l.timeouts)),
+            );
+        for (name, tags, zfs, timeouts) in volumes {
+            if !names.contains(name, tags) {
+                continue;
+            }
+            if let Some(Zfs {
+                volume,
+                retention: Some(retention),
+                ..
+            }) = zfs
+            {
+                let action = actions::ZfsPrune::new(None, volume, retention, &[])?;
+                let timeouts = timeouts.over(&self.config.timeouts);
+                runner.push_with(name, Policy::timeout(timeouts.prune), Box::new(action));
+            }
+        }
+        for (name, rep) in self.replications() {
+            if !names.contains(name, &rep.tags) {
+                continue;
+            }
+            let timeouts = rep.timeouts.over(&self.config.timeouts);
+            // Filesystems that aren't replicated aren't pruned on the
+            // source either.
+            let ends = [(&rep.src, &rep.excludes[..]), (&rep.dest, &[][..])];
+            for (end, excludes) in &ends {
+                if let Some(ref retention) = end.retention {
+                    let action = actions::ZfsPrune::new(
+                        end.host.as_deref(),
+                        &end.volume,
+                        retention,
+                        excludes,
+                    )?;
+                    runner.push_with(name, Policy::timeout(timeouts.prune), Box::new(action));
+                }
+            }
+        }
+        self.add_observers(&mut runner);
         Ok(runner)
     }
 
+    /// Write systemd units to run the scheduled profiles into `dir`.
+    /// `path` is the path of this config file, for the service to use.
+    pub fn systemd_generate(&self, path: &Path, dir: &Path, pretend: bool) -> Result<()> {
+        systemd::generate(&self.systemd, path, &self.timers(), dir, pretend)
+    }
+
+    /// Show the status of the timers of the scheduled profiles.
+    pub fn systemd_status(&self) -> Result<()> {
+        systemd::status(&self.timers())
+    }
+
+    /// The timers for each of the profiles with a schedule.
+    pub(crate) fn timers(&self) -> Vec<systemd::Timer<'_>> {
+        self.profiles
+            .iter()
+            .filter_map(|(name, profile)| {
+                profile.schedule.as_ref().map(|schedule| systemd::Timer {
+                    profile: name,
+                    schedule,
+                    randomized_delay: profile.randomized_delay,
+                })
+            })
+            .collect()
+    }
+
+    /// Iterate over the named ZFS replications.
+    fn replications(&self) -> impl Iterator<Item = (&str, &Replicate)> {
+        self.zfs
+            .iter()
+            .flat_map(|m| m.iter().map(|(name, rep)| (name.as_str(), rep)))
+    }
+
+    /// Add the observers that report on runs: progress, the history, and
+    /// the configured metrics and notifications.  The history must come
+    /// first, as the others use it.
+    fn add_observers(&self, runner: &mut Runner) {
+        runner.add_observer(Box::new(actions::Progress::new()));
+        runner.add_observer(Box::new(self.history()));
+
+        if let Some(ref dir) = self.config.metrics {
+            let volumes = self.volumes().iter().map(|v| v.name.to_string()).collect();
+            let replications = self
+                .replications()
+                .map(|(name, _)| name.to_string())
+                .collect();
+            runner.add_observer(Box::new(MetricsWriter::new(
+                Path::new(dir),
+                volumes,
+                replications,
+                self.history(),
+            )));
+        }
+
+        if let Some(ref notify) = self.notify {
+            runner.add_observer(Box::new(Notifier::new(
+                notify.mail.clone(),
+                notify.webhook.clone(),
+                self.history(),
+            )));
+        }
+    }
+
     /// Push a new runner, with a banner message for its name.
     fn add_runner(
         runners: &mut BTreeMap<Phase, Runner>,
@@ -131,30 +649,68 @@ impl Simple {
         &self,
         runners: &mut BTreeMap<Phase, Runner>,
         config: &ConfigFile,
+        steps: &Steps,
     ) -> Result<()> {
-        let a1 = actions::Stamp::new(&Path::new(&self.mount).join("snapstamp"))?;
-        runners
-            .get_mut(&Phase::Timestamp)
-            .unwrap()
-            .push(Box::new(a1));
+        let timeouts = self.timeouts.over(&config.config.timeouts);
 
-        let local = Utc::now().format("%Y%m%dT%H%M%S");
-        let a4 = actions::SimpleRsure::new(&self.mount, &format!("{}", local))?;
-        runners.get_mut(&Phase::Rsure).unwrap().push(Box::new(a4));
-
-        let backup_name = format!("{}-{}", self.name, local);
-        let a5 = actions::BorgBackup::new(&self.mount, &config.config.borg, &backup_name)?;
-        runners.get_mut(&Phase::Borg).unwrap().push(Box::new(a5));
+        if steps.backs_up() {
+            let a1 = actions::Stamp::new(&Path::new(&self.mount).join("snapstamp"))?;
+            runners
+                .get_mut(&Phase::Timestamp)
+                .unwrap()
+                .push_volume(&self.name, Box::new(a1));
+        }
 
-        if let Some(ref zfs) = self.zfs {
-            let a6 = actions::Rsync::new(&self.mount, &zfs.mount, false, false)?;
-            runners.get_mut(&Phase::Rsync).unwrap().push(Box::new(a6));
+        let local = timestamp::now();
+        if steps.has(Step::Rsure) {
+            let a4 = actions::SimpleRsure::new(&self.mount, &local)?;
+            runners.get_mut(&Phase::Rsure).unwrap().push_with(
+                &self.name,
+                Policy::timeout(timeouts.rsure),
+                Box::new(a4),
+            );
+        }
 
-            let a7 = actions::ZfsSnapshot::new(&zfs.volume, &format!("{}", local))?;
+        if steps.has(Step::Borg) {
+            let backup_name = format!("{}-{}", self.name, local);
+            let a5 = actions::BorgBackup::new(&self.mount, &config.config.borg, &backup_name)?;
+            let policy = Policy {
+                timeout: timeouts.borg,
+                retry: config.config.retries.borg,
+            };
             runners
-                .get_mut(&Phase::ZfsSnapshot)
+                .get_mut(&Phase::Borg)
                 .unwrap()
-                .push(Box::new(a7));
+                .push_with(&self.name, policy, Box::new(a5));
+        }
+
+        if let Some(ref zfs) = self.zfs {
+            if steps.has(Step::Rsync) {
+                let a6 = actions::Rsync::new(&self.mount, &zfs.mount, false, false)?;
+                runners.get_mut(&Phase::Rsync).unwrap().push_with(
+                    &self.name,
+                    Policy::timeout(timeouts.rsync),
+                    Box::new(a6),
+                );
+            }
+
+            if steps.has(Step::ZfsSnapshot) {
+                let a7 = actions::ZfsSnapshot::new(&zfs.volume, &local)?;
+                runners.get_mut(&Phase::ZfsSnapshot).unwrap().push_with(
+                    &self.name,
+                    Policy::timeout(timeouts.zfs_snapshot),
+                    Box::new(a7),
+                );
+            }
+
+            if let (true, Some(ref retention)) = (steps.has(Step::Prune), &zfs.retention) {
+                let a8 = actions::ZfsPrune::new(None, &zfs.volume, retention, &[])?;
+                runners.get_mut(&Phase::Prune).unwrap().push_with(
+                    &self.name,
+                    Policy::timeout(timeouts.prune),
+                    Box::new(a8),
+                );
+            }
         }
 
         Ok(())
@@ -166,65 +722,124 @@ impl Lvm {
         &self,
         runners: &mut BTreeMap<Phase, Runner>,
         config: &ConfigFile,
+        steps: &Steps,
     ) -> Result<()> {
-        let a1 = actions::Stamp::new(&Path::new(&self.mount).join("snapstamp"))?;
-        runners
-            .get_mut(&Phase::Timestamp)
-            .unwrap()
-            .push(Box::new(a1));
-
-        let a2 = actions::LvmSnapshot::new(&self.vg, &self.lv, &self.lv_snap)?;
-        runners
-            .get_mut(&Phase::Snapshot)
-            .unwrap()
-            .push(Box::new(a2));
-
-        let snap_device = format!("/dev/{}/{}", self.vg, self.lv_snap);
-        let a3 = actions::MountSnap::new(&snap_device, &self.snap, self.fs == "xfs")?;
-        runners.get_mut(&Phase::Mount).unwrap().push(Box::new(a3));
-
-        let local = Utc::now().format("%Y%m%dT%H%M%S");
-        let a4 = actions::LvmRsure::new(&self.mount, &self.snap, &format!("{}", local))?;
-        runners.get_mut(&Phase::Rsure).unwrap().push(Box::new(a4));
-
-        let backup_name = format!("{}-{}", self.name, local);
-        let a5 = actions::BorgBackup::new(&self.snap, &config.config.borg, &backup_name)?;
-        runners.get_mut(&Phase::Borg).unwrap().push(Box::new(a5));
+        let timeouts = self.timeouts.over(&config.config.timeouts);
 
-        if let Some(ref zfs) = self.zfs {
-            let a6 = actions::Rsync::new(&self.snap, &zfs.mount, true, false)?;
-            runners.get_mut(&Phase::Rsync).unwrap().push(Box::new(a6));
+        if steps.backs_up() {
+            let a1 = actions::Stamp::new(&Path::new(&self.mount).join("snapstamp"))?;
+            runners
+                .get_mut(&Phase::Timestamp)
+                .unwrap()
+                .push_volume(&self.name, Box::new(a1));
+        }
 
-            let a7 = actions::ZfsSnapshot::new(&zfs.volume, &format!("{}", local))?;
+        let snap = steps.has(Step::Snap)
+            || steps.has(Step::Rsure)
+            || steps.has(Step::Borg)
+            || (self.zfs.is_some() && steps.has(Step::Rsync));
+        if snap {
+            let a2 = actions::LvmSnapshot::new(&self.vg, &self.lv, &self.lv_snap)?;
+            runners.get_mut(&Phase::Snapshot).unwrap().push_with(
+                &self.name,
+                Policy::timeout(timeouts.snapshot),
+                Box::new(a2),
+            );
+
+            let snap_device = format!("/dev/{}/{}", self.vg, self.lv_snap);
+            let a3 = actions::MountSnap::new(&snap_device, &self.snap, self.fs == "xfs")?;
+            runners.get_mut(&Phase::Mount).unwrap().push_with(
+                &self.name,
+                Policy::timeout(timeouts.mount),
+                Box::new(a3),
+            );
+        }
+
+        let local = timestamp::now();
+        if steps.has(Step::Rsure) {
+            let a4 = actions::LvmRsure::new(&self.mount, &self.snap, &local)?;
+            runners.get_mut(&Phase::Rsure).unwrap().push_with(
+                &self.name,
+                Policy::timeout(timeouts.rsure),
+                Box::new(a4),
+            );
+        }
+
+        if steps.has(Step::Borg) {
+            let backup_name = format!("{}-{}", self.name, local);
+            let a5 = actions::BorgBackup::new(&self.snap, &config.config.borg, &backup_name)?;
+            let policy = Policy {
+                timeout: timeouts.borg,
+                retry: config.config.retries.borg,
+            };
             runners
-                .get_mut(&Phase::ZfsSnapshot)
+                .get_mut(&Phase::Borg)
                 .unwrap()
-                .push(Box::new(a7));
+                .push_with(&self.name, policy, Box::new(a5));
+        }
+
+        if let Some(ref zfs) = self.zfs {
+            if steps.has(Step::Rsync) {
+                let a6 = actions::Rsync::new(&self.snap, &zfs.mount, true, false)?;
+                runners.get_mut(&Phase::Rsync).unwrap().push_with(
+                    &self.name,
+                    Policy::timeout(timeouts.rsync),
+                    Box::new(a6),
+                );
+            }
+
+            if steps.has(Step::ZfsSnapshot) {
+                let a7 = actions::ZfsSnapshot::new(&zfs.volume, &local)?;
+                runners.get_mut(&Phase::ZfsSnapshot).unwrap().push_with(
+                    &self.name,
+                    Policy::timeout(timeouts.zfs_snapshot),
+                    Box::new(a7),
+                );
+            }
+
+            if let (true, Some(ref retention)) = (steps.has(Step::Prune), &zfs.retention) {
+                let a8 = actions::ZfsPrune::new(None, &zfs.volume, retention, &[])?;
+                runners.get_mut(&Phase::Prune).unwrap().push_with(
+                    &self.name,
+                    Policy::timeout(timeouts.prune),
+                    Box::new(a8),
+                );
+            }
         }
 
         Ok(())
     }
 }
 
-struct NameFilter<'a> {
-    names: Option<HashSet<&'a str>>,
-}
-
-impl<'a> NameFilter<'a> {
-    fn new<'b>(names: &[&'b str]) -> NameFilter<'b> {
-        if names.len() == 0 {
-            NameFilter { names: None }
-        } else {
-            NameFilter {
-                names: Some(names.iter().cloned().collect()),
-            }
+impl<'a> Steps<'a> {
+    /// Is this step to be performed?  Pruning deletes snapshots, so is
+    /// only done when asked for by name.
+    fn has(&self, step: Step) -> bool {
+        match self.0 {
+            None => step != Step::Prune,
+            Some(steps) => steps.contains(&step),
         }
     }
 
-    fn contains(&self, name: &str) -> bool {
-        match self.names {
-            None => true,
-            Some(ref set) => set.contains(name),
+    /// Is the volume backed up?  The timestamp records when it last was,
+    /// so is only updated when it is.
+    fn backs_up(&self) -> bool {
+        self.has(Step::Snap) || self.has(Step::Rsure) || self.has(Step::Borg)
+    }
+}
+
+impl Timeouts {
+    /// Return these timeouts, with any not given taken from `defaults`.
+    fn over(&self, defaults: &Timeouts) -> Timeouts {
+        Timeouts {
+            snapshot: self.snapshot.or(defaults.snapshot),
+            mount: self.mount.or(defaults.mount),
+            rsure: self.rsure.or(defaults.rsure),
+            borg: self.borg.or(defaults.borg),
+            rsync: self.rsync.or(defaults.rsync),
+            zfs_snapshot: self.zfs_snapshot.or(defaults.zfs_snapshot),
+            replicate: self.replicate.or(defaults.replicate),
+            prune: self.prune.or(defaults.prune),
         }
     }
 }
@@ -234,3 +849,78 @@ impl Actions {
         self.0.iter().find(|s| s.as_str() == item).is_some()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        let all = Steps(None);
+        assert!(all.has(Step::Borg) && all.has(Step::ZfsSnapshot) && all.backs_up());
+        assert!(!all.has(Step::Prune));
+
+        let prune = Steps(Some(&[Step::Prune]));
+        assert!(prune.has(Step::Prune));
+        assert!(!prune.has(Step::Borg) && !prune.backs_up());
+    }
+
+    fn clone_config() -> ConfigFile {
+        serde_yaml::from_str(
+            "config:
+  borg: /bin/sh
+simple: []
+lvm: []
+zfs:
+  - media:
+      src: {volume: tank/media}
+      dest: {host: vaco, volume: backup/media}
+      tags: [bulk]
+    home:
+      src: {host: laptop, volume: rpool/home}
+      dest: {volume: backup/home}
+      raw: true
+  - photos:
+      src: {volume: tank/photos}
+      dest: {volume: backup/photos}
+      tags: [bulk]
+",
+        )
+        .unwrap()
+    }
+
+    /// The replications a clone of the selected names would perform.
+    fn cloned(names: &[&str]) -> Vec<String> {
+        let runner = clone_config().build_clone_runner(names).unwrap();
+        let actions = runner.describe();
+        assert_eq!(actions[0], (None, "    running: Replicate".to_string()));
+        actions[1..]
+            .iter()
+            .map(|(volume, desc)| format!("{}: {}", volume.unwrap(), desc))
+            .collect()
+    }
+
+    #[test]
+    fn clone_runner() {
+        assert_eq!(
+            cloned(&[]),
+            vec![
+                "home: Zfs replicate laptop:rpool/home to backup/home (raw)",
+                "media: Zfs replicate tank/media to vaco:backup/media",
+                "photos: Zfs replicate tank/photos to backup/photos",
+            ]
+        );
+        assert_eq!(
+            cloned(&["@bulk"]),
+            vec![
+                "media: Zfs replicate tank/media to vaco:backup/media",
+                "photos: Zfs replicate tank/photos to backup/photos",
+            ]
+        );
+        assert_eq!(
+            cloned(&["home"]),
+            vec!["home: Zfs replicate laptop:rpool/home to backup/home (raw)"]
+        );
+        assert!(clone_config().build_clone_runner(&["music"]).is_err());
+    }
+}
@@ -0,0 +1,916 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Config file checks.
+//!
+//! Loading the config stops at the first problem with its shape.  Checking
+//! it instead parses the file into a tree of nodes that know where they
+//! are in the file, and decodes each section, and each entry of the lists
+//! and maps within them, on its own, so that a problem with one entry
+//! doesn't hide those with the others.
+//!
+//! Once the whole file decodes, further checks look for problems that
+//! would otherwise only be found partway through a backup, such as a
+//! missing mount point, or a logical volume that doesn't exist.  All of
+//! the problems found are reported, each with the location in the file
+//! that it comes from.
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{Command, Stdio},
+};
+use yaml_rust::{
+    parser::{Event, Parser},
+    scanner::{Marker, ScanError},
+};
+
+use super::{Config, ConfigFile, Lvm, Maintain, Notify, Profile, Replicate, Simple};
+use crate::{checked::CheckedExt, freshness::AgeLimits, systemd};
+
+/// The filesystems that snapshots can be mounted as.
+static FILESYSTEMS: &[&str] = &["ext2", "ext3", "ext4", "xfs"];
+
+/// A problem found in the config file.
+#[derive(Debug)]
+pub struct Problem {
+    /// The line (counting from 1) and column, if known.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    /// Format the problem as coming from the given file, in the usual
+    /// `file:line:column: message` form.
+    pub fn located(&self, file: &str) -> String {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}: {}", file, line, column, self.message),
+            (Some(line), None) => format!("{}:{}: {}", file, line, self.message),
+            _ => format!("{}: {}", file, self.message),
+        }
+    }
+
+    /// A problem from a yaml error at the given line and column, which
+    /// also end its message.
+    fn from_yaml(context: Option<&str>, message: String, at: Option<(usize, usize)>) -> Problem {
+        let mut message = message;
+        if let Some((line, column)) = at {
+            let suffix = format!(" at line {} column {}", line, column);
+            if message.ends_with(&suffix) {
+                message.truncate(message.len() - suffix.len());
+            }
+        }
+        if let Some(context) = context {
+            message = format!("{}: {}", context, message);
+        }
+        Problem {
+            line: at.map(|(line, _)| line),
+            column: at.map(|(_, column)| column),
+            message,
+        }
+    }
+}
+
+impl Problem {
+    /// Errors about a whole map, such as a missing field, or an invalid
+    /// value decoded from a string, are located where yaml marks the map.
+    /// For a block map, that is at the `:` after its first key, so they are
+    /// moved to the key, where the map starts.
+    fn at_map_start(mut self, node: &Node) -> Problem {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            if let Some(map) = node.map_marked(line, column) {
+                self.line = Some(map.marker.line());
+                self.column = Some(map.marker.col() + 1);
+            }
+        }
+        self
+    }
+}
+
+impl ConfigFile {
+    /// Load the config file at `path` and check it, returning all of the
+    /// problems found.  Errors are only returned if the file can't be
+    /// read.
+    pub fn check<P: AsRef<Path>>(path: P) -> Result<Vec<Problem>> {
+        Ok(ConfigFile::check_text(&fs::read_to_string(path)?))
+    }
+
+    /// Check the text of a config file, returning all of the problems
+    /// found.
+    fn check_text(text: &str) -> Vec<Problem> {
+        let root = match Node::parse(text) {
+            Ok(root) => root,
+            Err(err) => {
+                let at = (err.marker().line(), err.marker().col() + 1);
+                let problem = Problem::from_yaml(None, err.to_string(), Some(at));
+                return vec![problem];
+            }
+        };
+        let mut checker = Checker {
+            source: text.chars().collect(),
+            problems: vec![],
+        };
+
+        if let Some(ref root) = root {
+            checker.check_sections(root);
+        }
+
+        // The whole file also has to decode, which finds problems outside
+        // of the sections, such as unknown or missing ones.  Problems
+        // already found in a section are found again, so are dropped.
+        let config: ConfigFile = match serde_yaml::from_str(text) {
+            Ok(config) => config,
+            Err(err) => {
+                let mut problem = Problem::from_yaml(None, err.to_string(), location(&err));
+                if let Some(ref root) = root {
+                    problem = problem.at_map_start(root);
+                }
+                let known = checker
+                    .problems
+                    .iter()
+                    .any(|p| (p.line, p.column) == (problem.line, problem.column));
+                if !known {
+                    checker.problems.push(problem);
+                }
+                return checker.problems;
+            }
+        };
+
+        // The file decoded, so has the expected shape, and a document.
+        let root = match root {
+            Some(root) => root,
+            None => return checker.problems,
+        };
+        let mut checker = Located {
+            root: &root,
+            problems: checker.problems,
+        };
+        config.check_names(&mut checker);
+        config.check_paths(&mut checker);
+        config.check_lvm(&mut checker);
+        config.check_borg(&mut checker);
+        checker.problems
+    }
+
+    /// Volume names are used for archive names, the history, and metrics,
+    /// so must be unique, including the names of zfs replications.
+    fn check_names(&self, checker: &mut Located) {
+        let volumes: Vec<_> = self.volumes().iter().map(|v| v.name).collect();
+        let replications: Vec<_> = self.replications().map(|(name, _)| name).collect();
+        let names: Vec<_> = volumes.iter().chain(replications.iter()).cloned().collect();
+        for name in duplicates(&names) {
+            let message = format!("volume name {:?} is used more than once", name);
+            for (index, simp) in self.simple.iter().enumerate() {
+                if simp.name == name {
+                    checker.add(checker.volume("simple", index, "name"), &message);
+                }
+            }
+            for (index, lvm) in self.lvm.iter().enumerate() {
+                if lvm.name == name {
+                    checker.add(checker.volume("lvm", index, "name"), &message);
+                }
+            }
+            for node in checker.replications(name) {
+                checker.add(Some(node), &message);
+            }
+        }
+
+        // Each tag of a profile should select something.
+        let tagged: Vec<_> = self.volumes().iter().map(|v| v.tags).collect();
+        for (name, profile) in &self.profiles {
+            for (index, tag) in profile.tags.iter().enumerate() {
+                if !tagged.iter().any(|tags| tags.contains(tag)) {
+                    let message = format!("profile {:?}: no volume has the tag {:?}", name, tag);
+                    let node = checker
+                        .root
+                        .get("profiles")
+                        .and_then(|p| p.get(name))
+                        .and_then(|p| p.get("tags"))
+                        .and_then(|t| t.index(index));
+                    checker.add(node, &message);
+                }
+            }
+        }
+
+        let snaps: Vec<_> = self.lvm.iter().map(|l| l.snap.as_str()).collect();
+        for snap in duplicates(&snaps) {
+            let message = format!("snapshot mount point {:?} is used more than once", snap);
+            for (index, lvm) in self.lvm.iter().enumerate() {
+                if lvm.snap == snap {
+                    checker.add(checker.volume("lvm", index, "snap"), &message);
+                }
+            }
+        }
+
+        // Snapshot LVs only need to be unique within their VG.
+        let lv_snaps: Vec<_> = self
+            .lvm
+            .iter()
+            .map(|l| format!("{}/{}", l.vg, l.lv_snap))
+            .collect();
+        let names: Vec<_> = lv_snaps.iter().map(|s| s.as_str()).collect();
+        for lv_snap in duplicates(&names) {
+            let message = format!("snapshot LV {:?} is used more than once", lv_snap);
+            for (index, name) in lv_snaps.iter().enumerate() {
+                if name == lv_snap {
+                    checker.add(checker.volume("lvm", index, "lv_snap"), &message);
+                }
+            }
+        }
+    }
+
+    /// The directories that are backed up, and mounted on, must exist.
+    fn check_paths(&self, checker: &mut Located) {
+        let mut dirs = vec![];
+        for (index, simp) in self.simple.iter().enumerate() {
+            dirs.push((checker.volume("simple", index, "mount"), &simp.mount));
+            if let Some(ref zfs) = simp.zfs {
+                let node = checker.volume("simple", index, "zfs");
+                dirs.push((node.and_then(|z| z.get("mount")), &zfs.mount));
+            }
+        }
+        for (index, lvm) in self.lvm.iter().enumerate() {
+            dirs.push((checker.volume("lvm", index, "mount"), &lvm.mount));
+            dirs.push((checker.volume("lvm", index, "snap"), &lvm.snap));
+            if let Some(ref zfs) = lvm.zfs {
+                let node = checker.volume("lvm", index, "zfs");
+                dirs.push((node.and_then(|z| z.get("mount")), &zfs.mount));
+            }
+        }
+        for (node, dir) in dirs {
+            if !Path::new(dir).is_dir() {
+                checker.add(node, &format!("directory {:?} does not exist", dir));
+            }
+        }
+    }
+
+    /// The volumes to snapshot must exist, and be mountable.
+    fn check_lvm(&self, checker: &mut Located) {
+        for (index, lvm) in self.lvm.iter().enumerate() {
+            if !FILESYSTEMS.contains(&lvm.fs.as_str()) {
+                let message = format!(
+                    "unknown filesystem {:?}, expecting one of: {}",
+                    lvm.fs,
+                    FILESYSTEMS.join(", ")
+                );
+                checker.add(checker.volume("lvm", index, "fs"), &message);
+            }
+        }
+
+        if self.lvm.is_empty() {
+            return;
+        }
+        let lvs = match logical_volumes() {
+            Ok(lvs) => lvs,
+            Err(err) => {
+                let message = format!("unable to check logical volumes: {:#}", err);
+                checker.add(None, &message);
+                return;
+            }
+        };
+        for (index, lvm) in self.lvm.iter().enumerate() {
+            match lvs.get(&lvm.vg) {
+                None => {
+                    let message = format!("volume group {:?} does not exist", lvm.vg);
+                    checker.add(checker.volume("lvm", index, "vg"), &message);
+                }
+                Some(names) if !names.contains(&lvm.lv) => {
+                    let message = format!("logical volume {}/{} does not exist", lvm.vg, lvm.lv);
+                    checker.add(checker.volume("lvm", index, "lv"), &message);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// The borg script must be there to run.
+    fn check_borg(&self, checker: &mut Located) {
+        let borg = &self.config.borg;
+        let message = match fs::metadata(borg) {
+            Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => return,
+            Ok(_) => format!("borg script {:?} is not executable", borg),
+            Err(err) => format!("borg script {:?}: {}", borg, err),
+        };
+        let node = checker.root.get("config").and_then(|c| c.get("borg"));
+        checker.add(node, &message);
+    }
+}
+
+/// Decodes the sections of the file, collecting the problems found.
+struct Checker {
+    /// The text of the file, by character, as yaml locations count them.
+    source: Vec<char>,
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    /// Decode each of the sections, and each entry within them.  Sections
+    /// that aren't known are left to the decoding of the whole file.
+    fn check_sections(&mut self, root: &Node) {
+        for (key, value) in root.entries() {
+            match key.scalar() {
+                Some("config") => self.decode::<Config>(value, "config"),
+                Some("simple") => self.decode_each::<Simple>(value, "simple"),
+                Some("lvm") => self.decode_each::<Lvm>(value, "lvm"),
+                Some("maintain") => self.decode::<Option<Maintain>>(value, "maintain"),
+                Some("freshness") => self.decode::<AgeLimits>(value, "freshness"),
+                Some("notify") => self.decode::<Option<Notify>>(value, "notify"),
+                Some("zfs") => match value.kind {
+                    Kind::Seq(ref items) => {
+                        for (index, item) in items.iter().enumerate() {
+                            let context = format!("zfs[{}]", index);
+                            self.decode_values::<Replicate>(item, &context);
+                        }
+                    }
+                    _ => self.decode::<Vec<BTreeMap<String, Replicate>>>(value, "zfs"),
+                },
+                Some("profiles") => self.decode_values::<Profile>(value, "profiles"),
+                Some("systemd") => self.decode::<systemd::Service>(value, "systemd"),
+                _ => (),
+            }
+        }
+    }
+
+    /// Decode a node as a `T`, adding any problem found.  The problem is
+    /// described within the given context.  Aliases refer to anchors
+    /// elsewhere in the file, so nodes with them are left to the decoding
+    /// of the whole file.
+    fn decode<T: DeserializeOwned>(&mut self, node: &Node, context: &str) {
+        if node.has_alias() {
+            return;
+        }
+        if let Err(err) = serde_yaml::from_str::<T>(&node.text(&self.source)) {
+            let problem = Problem::from_yaml(Some(context), err.to_string(), location(&err));
+            self.problems.push(problem.at_map_start(node));
+        }
+    }
+
+    /// Decode each item of a list as a `T`.
+    fn decode_each<T: DeserializeOwned>(&mut self, node: &Node, context: &str) {
+        match node.kind {
+            Kind::Seq(ref items) => {
+                for (index, item) in items.iter().enumerate() {
+                    self.decode::<T>(item, &format!("{}[{}]", context, index));
+                }
+            }
+            _ => self.decode::<Vec<T>>(node, context),
+        }
+    }
+
+    /// Decode each value of a map as a `T`.
+    fn decode_values<T: DeserializeOwned>(&mut self, node: &Node, context: &str) {
+        match node.kind {
+            Kind::Map(ref entries) => {
+                for (key, value) in entries {
+                    let name = key.scalar().unwrap_or_default();
+                    self.decode::<T>(value, &format!("{}.{}", context, name));
+                }
+            }
+            _ => self.decode::<BTreeMap<String, T>>(node, context),
+        }
+    }
+}
+
+/// Collects problems, located by the nodes they come from.
+struct Located<'a> {
+    root: &'a Node,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Located<'a> {
+    /// Add a problem at the given node, if it is known.
+    fn add(&mut self, node: Option<&Node>, message: &str) {
+        self.problems.push(Problem {
+            line: node.map(|n| n.marker.line()),
+            column: node.map(|n| n.marker.col() + 1),
+            message: message.to_string(),
+        });
+    }
+
+    /// The value of `key` in the volume at `index` of the `kind` list.
+    fn volume(&self, kind: &str, index: usize, key: &str) -> Option<&'a Node> {
+        self.root
+            .get(kind)
+            .and_then(|v| v.index(index))
+            .and_then(|v| v.get(key))
+    }
+
+    /// The names of the zfs replications with the given name.
+    fn replications(&self, name: &str) -> Vec<&'a Node> {
+        let items = match self.root.get("zfs").map(|z| &z.kind) {
+            Some(Kind::Seq(items)) => &items[..],
+            _ => &[],
+        };
+        items
+            .iter()
+            .flat_map(|item| item.entries())
+            .filter(|(key, _)| key.scalar() == Some(name))
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+/// A node of the yaml document, with where it is in the source.
+struct Node {
+    /// Where the node starts.
+    marker: Marker,
+    /// Where yaml marks the node as starting.  This is the same, except
+    /// for a block map, which is marked at the `:` after its first key.
+    marked: Marker,
+    /// The offset, in characters, of whatever follows it.
+    end: usize,
+    kind: Kind,
+}
+
+enum Kind {
+    Scalar(String),
+    Seq(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+    /// A reference to an anchor, which isn't followed.
+    Alias,
+}
+
+impl Node {
+    /// Parse the first document of the text, if it has one.
+    fn parse(text: &str) -> std::result::Result<Option<Node>, ScanError> {
+        let mut parser = Parser::new(text.chars());
+        let mut events = vec![];
+        loop {
+            let (event, marker) = parser.next()?;
+            let done = event == Event::StreamEnd;
+            events.push((event, marker));
+            if done {
+                break;
+            }
+        }
+        let mut pos = 0;
+        while pos < events.len() {
+            match events[pos].0 {
+                Event::StreamStart | Event::DocumentStart => pos += 1,
+                Event::StreamEnd => return Ok(None),
+                _ => return Ok(Some(Node::build(&events, &mut pos))),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Build the node whose events start at `pos`, leaving `pos` after
+    /// them.  The events of the document always end with a DocumentEnd
+    /// and StreamEnd, so there is always an event after the node.
+    fn build(events: &[(Event, Marker)], pos: &mut usize) -> Node {
+        let (ref event, marker) = events[*pos];
+        *pos += 1;
+        let kind = match *event {
+            Event::Scalar(ref value, ..) => Kind::Scalar(value.clone()),
+            Event::SequenceStart(_) => {
+                let mut items = vec![];
+                while events[*pos].0 != Event::SequenceEnd {
+                    items.push(Node::build(events, pos));
+                }
+                *pos += 1;
+                Kind::Seq(items)
+            }
+            Event::MappingStart(_) => {
+                let mut entries = vec![];
+                while events[*pos].0 != Event::MappingEnd {
+                    let key = Node::build(events, pos);
+                    let value = Node::build(events, pos);
+                    entries.push((key, value));
+                }
+                *pos += 1;
+                Kind::Map(entries)
+            }
+            _ => Kind::Alias,
+        };
+        let start = match kind {
+            Kind::Map(ref entries) if !entries.is_empty() => {
+                let key = entries[0].0.marker;
+                if key.index() < marker.index() {
+                    key
+                } else {
+                    marker
+                }
+            }
+            _ => marker,
+        };
+        Node {
+            marker: start,
+            marked: marker,
+            end: Node::start(events, *pos),
+            kind,
+        }
+    }
+
+    /// The offset of the events starting at `pos`.  A block map is only
+    /// known to be one after its first key, so starts at that key rather
+    /// than where it is marked.
+    fn start(events: &[(Event, Marker)], pos: usize) -> usize {
+        let index = events[pos].1.index();
+        match events[pos].0 {
+            Event::MappingStart(_) if events[pos + 1].0 != Event::MappingEnd => {
+                index.min(Node::start(events, pos + 1))
+            }
+            _ => index,
+        }
+    }
+
+    /// Find the map in this tree that yaml marks at the given line and
+    /// column (counting from 1).
+    fn map_marked(&self, line: usize, column: usize) -> Option<&Node> {
+        let children: Vec<&Node> = match self.kind {
+            Kind::Map(ref entries) => {
+                if (self.marked.line(), self.marked.col() + 1) == (line, column) {
+                    return Some(self);
+                }
+                entries.iter().flat_map(|(k, v)| vec![k, v]).collect()
+            }
+            Kind::Seq(ref items) => items.iter().collect(),
+            _ => vec![],
+        };
+        children
+            .into_iter()
+            .find_map(|child| child.map_marked(line, column))
+    }
+
+    fn has_alias(&self) -> bool {
+        match self.kind {
+            Kind::Scalar(_) => false,
+            Kind::Seq(ref items) => items.iter().any(|item| item.has_alias()),
+            Kind::Map(ref entries) => entries.iter().any(|(k, v)| k.has_alias() || v.has_alias()),
+            Kind::Alias => true,
+        }
+    }
+
+    /// The text of the node, as a document of its own.  Everything before
+    /// it is blanked out, so that the locations of any errors decoding it
+    /// are those in the whole file.
+    fn text(&self, source: &[char]) -> String {
+        // The node runs up to whatever follows it, which may be after the
+        // `-` of the next item of a list, or the `,` in a flow.
+        let mut body: String = source[self.marker.index()..self.end].iter().collect();
+        body.truncate(body.trim_end().len());
+        if let Some(newline) = body.rfind('\n') {
+            if body[newline..].trim() == "-" {
+                body.truncate(body[..newline].trim_end().len());
+            }
+        }
+        if body.ends_with(',') {
+            body.pop();
+        }
+
+        let mut text = "\n".repeat(self.marker.line() - 1);
+        text.push_str(&" ".repeat(self.marker.col()));
+        text.push_str(&body);
+        text.push('\n');
+        text
+    }
+
+    fn scalar(&self) -> Option<&str> {
+        match self.kind {
+            Kind::Scalar(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The entries of a map, or none for other nodes.
+    fn entries(&self) -> impl Iterator<Item = (&Node, &Node)> {
+        let entries = match self.kind {
+            Kind::Map(ref entries) => &entries[..],
+            _ => &[],
+        };
+        entries.iter().map(|(key, value)| (key, value))
+    }
+
+    /// The value of `key`, in a map.
+    fn get(&self, key: &str) -> Option<&Node> {
+        self.entries()
+            .find(|(k, _)| k.scalar() == Some(key))
+            .map(|(_, value)| value)
+    }
+
+    /// The item at `index`, in a list.
+    fn index(&self, index: usize) -> Option<&Node> {
+        match self.kind {
+            Kind::Seq(ref items) => items.get(index),
+            _ => None,
+        }
+    }
+}
+
+/// The line and column of a serde_yaml error, if it has them.
+fn location(err: &serde_yaml::Error) -> Option<(usize, usize)> {
+    err.location().map(|loc| (loc.line(), loc.column()))
+}
+
+/// Return the items that occur more than once.
+fn duplicates<'a>(items: &[&'a str]) -> Vec<&'a str> {
+    let mut seen = HashSet::new();
+    let mut dups = vec![];
+    for &item in items {
+        if !seen.insert(item) && !dups.contains(&item) {
+            dups.push(item);
+        }
+    }
+    dups
+}
+
+/// Query the logical volumes on this system, by volume group.
+fn logical_volumes() -> Result<BTreeMap<String, HashSet<String>>> {
+    let out = Command::new("lvs")
+        .args(["--noheadings", "-o", "vg_name,lv_name"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .checked_output()?;
+    let mut lvs: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if let [vg, lv] = fields[..] {
+            lvs.entry(vg.to_string())
+                .or_default()
+                .insert(lv.to_string());
+        }
+    }
+    Ok(lvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The problems found in the config, as reported.  Those from checking
+    /// the logical volumes depend on the host, so are left out.
+    fn problems(text: &str) -> Vec<String> {
+        ConfigFile::check_text(text)
+            .iter()
+            .filter(|p| {
+                !p.message.contains("logical volume") && !p.message.contains("volume group")
+            })
+            .map(|p| p.located("rdump.yaml"))
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let text = "config:
+  borg: /bin/sh
+simple:
+  - name: boot
+    mount: /
+    tags: [system]
+    actions: [rsure, borg]
+lvm: []
+profiles:
+  nightly: {tags: [system], schedule: daily}
+";
+        assert_eq!(problems(text), Vec::<String>::new());
+        assert_eq!(problems(""), vec!["rdump.yaml: EOF while parsing a value"]);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let text = "config:
+  borg: /bin/sh
+  colour: blue
+simple:
+  - name: boot
+    mount: /
+    actions: [rsure, borg]
+  - {name: efi, mount: /, actions: [borg], extra: 1}
+lvm: []
+";
+        assert_eq!(
+            problems(text),
+            vec![
+                "rdump.yaml:3:3: config: unknown field `colour`, expected one of `borg`, \
+                 `state`, `history`, `metrics`, `lock`, `lock_wait`, `timeouts`, `retries`",
+                "rdump.yaml:8:44: simple[1]: unknown field `extra`, expected one of `name`, \
+                 `mount`, `tags`, `actions`, `zfs`, `timeouts`",
+            ]
+        );
+
+        // Sections are only checked by the decoding of the whole file.
+        let text = "config:
+  borg: /bin/sh
+simple: []
+lvm: []
+colour: blue
+";
+        assert_eq!(
+            problems(text),
+            vec![
+                "rdump.yaml:5:1: unknown field `colour`, expected one of `config`, `simple`, \
+                 `lvm`, `maintain`, `freshness`, `notify`, `zfs`, `profiles`, `systemd`",
+            ]
+        );
+        let text = "config:
+  borg: /bin/sh
+simple: []
+";
+        assert_eq!(problems(text), vec!["rdump.yaml:1:1: missing field `lvm`"]);
+    }
+
+    #[test]
+    fn wrong_types() {
+        let text = "config: {borg: /bin/sh, state: [1]}
+simple: []
+lvm: []
+zfs:
+  - media:
+      src: {volume: a}
+      dest: {volume: b}
+      buffer: [1]
+profiles:
+  nightly:
+    tags: system
+  hourly:
+    actions: [snap]
+    schedule: 17
+freshness:
+  backup: {warn: 2d, crit: soon}
+";
+        assert_eq!(
+            problems(text),
+            vec![
+                "rdump.yaml:1:32: config: state: invalid type: sequence, expected a string",
+                "rdump.yaml:8:15: zfs[0].media: buffer: invalid type: sequence, expected a string",
+                "rdump.yaml:11:11: profiles.nightly: tags: invalid type: string \"system\", \
+                 expected a sequence",
+                // Values decoded from strings are located by the map they
+                // are in.
+                "rdump.yaml:13:5: profiles.hourly: Schedule \"17\" needs a time of hour:minute",
+                "rdump.yaml:16:11: freshness: backup: Invalid period \"soon\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn list_items() {
+        // Each item is decoded on its own, up to the `-` of the next.
+        let text = "config:
+  borg: /bin/sh
+simple: [{name: a, mount: /, actions: [borg], bad: 1}, {name: b, mount: /, actions: [borg]}]
+lvm:
+  - name: root
+    mount: /
+    snap: /tmp
+    vg: vg
+    lv: root
+    lv_snap: root_snap
+    fs: xfs
+    extra: 1
+  - name: home
+    mount: /
+    vg: vg
+    lv: home
+    lv_snap: home_snap
+    fs: xfs
+    actions: [snap]
+zfs:
+  - one:
+      src: {volume: a}
+      dest: {volume: b, colour: red}
+    two:
+      src: {volume: a}
+";
+        assert_eq!(
+            problems(text),
+            vec![
+                "rdump.yaml:3:47: simple[0]: unknown field `bad`, expected one of `name`, \
+                 `mount`, `tags`, `actions`, `zfs`, `timeouts`",
+                "rdump.yaml:12:5: lvm[0]: unknown field `extra`, expected one of `name`, \
+                 `mount`, `tags`, `snap`, `vg`, `lv`, `lv_snap`, `fs`, `actions`, `zfs`, \
+                 `timeouts`",
+                "rdump.yaml:13:5: lvm[1]: missing field `snap`",
+                "rdump.yaml:23:25: zfs[0].one: dest: unknown field `colour`, expected one of \
+                 `host`, `volume`, `retention`",
+                "rdump.yaml:25:7: zfs[0].two: missing field `dest`",
+            ]
+        );
+
+        // A problem found again by decoding the whole file is only reported
+        // once.
+        let text = "config:
+  borg: /bin/sh
+simple:
+  - name: boot
+    actions: [borg]
+lvm: []
+";
+        assert_eq!(
+            problems(text),
+            vec!["rdump.yaml:4:5: simple[0]: missing field `mount`"]
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let text = "config:
+  borg: /bin/sh
+simple:
+  - name: boot
+    mount: /
+    actions: [rsure]
+  - {name: boot, mount: /tmp, actions: [rsure]}
+lvm:
+  - name: home
+    mount: /
+    snap: /tmp
+    vg: vg
+    lv: home
+    lv_snap: snap
+    fs: xfs
+    actions: [snap]
+  - name: var
+    mount: /
+    snap: /tmp
+    vg: vg
+    lv: var
+    lv_snap: snap
+    fs: btrfs
+    actions: [snap]
+zfs:
+  - home:
+      src: {volume: a}
+      dest: {volume: b}
+profiles:
+  nightly:
+    tags: [none]
+";
+        assert_eq!(
+            problems(text),
+            vec![
+                "rdump.yaml:4:11: volume name \"boot\" is used more than once",
+                "rdump.yaml:7:12: volume name \"boot\" is used more than once",
+                "rdump.yaml:9:11: volume name \"home\" is used more than once",
+                "rdump.yaml:26:5: volume name \"home\" is used more than once",
+                "rdump.yaml:31:12: profile \"nightly\": no volume has the tag \"none\"",
+                "rdump.yaml:11:11: snapshot mount point \"/tmp\" is used more than once",
+                "rdump.yaml:19:11: snapshot mount point \"/tmp\" is used more than once",
+                "rdump.yaml:14:14: snapshot LV \"vg/snap\" is used more than once",
+                "rdump.yaml:22:14: snapshot LV \"vg/snap\" is used more than once",
+                "rdump.yaml:23:9: unknown filesystem \"btrfs\", expecting one of: ext2, ext3, \
+                 ext4, xfs",
+            ]
+        );
+    }
+
+    #[test]
+    fn aliases() {
+        // The item with the alias is left to the decoding of the whole
+        // file, which finds the problem already found at the anchor.
+        let text = "config:
+  borg: /bin/sh
+simple:
+  - name: boot
+    mount: &mount [1]
+    actions: [rsure]
+  - name: efi
+    mount: *mount
+    actions: [rsure]
+lvm: []
+";
+        assert_eq!(
+            problems(text),
+            vec!["rdump.yaml:5:19: simple[0]: mount: invalid type: sequence, expected a string"]
+        );
+    }
+
+    #[test]
+    fn paths() {
+        let text = "config:
+  borg: /nonexistent/borg.sh
+simple:
+  - name: boot
+    mount: /nonexistent/boot
+    actions: [rsure]
+lvm: []
+";
+        assert_eq!(
+            problems(text),
+            vec![
+                "rdump.yaml:5:12: directory \"/nonexistent/boot\" does not exist",
+                "rdump.yaml:2:9: borg script \"/nonexistent/borg.sh\": No such file or \
+                 directory (os error 2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax() {
+        let text = "config:
+  borg: /bin/sh
+simple:
+  - name: boot
+    mount: /
+    actions: [rsure
+lvm: []
+";
+        assert_eq!(
+            problems(text),
+            vec!["rdump.yaml:7:4: while parsing a flow sequence, expected ',' or ']'"]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Selecting volumes by name.
+//!
+//! The volumes given on the command line can be exact names, glob
+//! patterns such as `boot*`, or tags such as `@system`, which select
+//! every volume carrying that tag.  No selection at all selects every
+//! volume.  Each selection must match at least one volume, so that a
+//! mistyped name is an error, rather than quietly backing up nothing.
+
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use std::fmt;
+
+pub(super) struct NameFilter {
+    selections: Vec<Selection>,
+}
+
+enum Selection {
+    Name(String),
+    Glob(Pattern),
+    Tag(String),
+}
+
+impl NameFilter {
+    pub(super) fn new(names: &[&str]) -> Result<NameFilter> {
+        let mut selections = vec![];
+        for &name in names {
+            let sel = if let Some(tag) = name.strip_prefix('@') {
+                Selection::Tag(tag.into())
+            } else if name.contains(['*', '?', '[']) {
+                Selection::Glob(
+                    Pattern::new(name)
+                        .map_err(|e| anyhow!("Invalid volume pattern {:?}: {}", name, e))?,
+                )
+            } else {
+                Selection::Name(name.into())
+            };
+            selections.push(sel);
+        }
+        Ok(NameFilter { selections })
+    }
+
+    /// Is the volume with this name and tags selected?
+    pub(super) fn contains(&self, name: &str, tags: &[String]) -> bool {
+        self.selections.is_empty() || self.selections.iter().any(|s| s.matches(name, tags))
+    }
+
+    /// Check that every selection matches at least one of the given
+    /// volumes, as name and tags.  `kind` describes the volumes, for the
+    /// error.
+    pub(super) fn check(&self, volumes: &[(&str, &[String])], kind: &str) -> Result<()> {
+        let unmatched: Vec<_> = self
+            .selections
+            .iter()
+            .filter(|s| !volumes.iter().any(|(name, tags)| s.matches(name, tags)))
+            .map(|s| s.to_string())
+            .collect();
+        if unmatched.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("No {} matches: {}", kind, unmatched.join(", ")))
+        }
+    }
+}
+
+impl Selection {
+    fn matches(&self, name: &str, tags: &[String]) -> bool {
+        match self {
+            Selection::Name(n) => n == name,
+            Selection::Glob(pat) => pat.matches(name),
+            Selection::Tag(tag) => tags.contains(tag),
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selection::Name(n) => write!(f, "{}", n),
+            Selection::Glob(pat) => write!(f, "{}", pat),
+            Selection::Tag(tag) => write!(f, "@{}", tag),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn everything() {
+        let names = NameFilter::new(&[]).unwrap();
+        assert!(names.contains("home", &[]));
+        assert!(names.check(&[], "volume").is_ok());
+    }
+
+    #[test]
+    fn matching() {
+        let system = tags(&["system"]);
+        let names = NameFilter::new(&["home", "boot*", "@system"]).unwrap();
+        assert!(names.contains("home", &[]));
+        assert!(names.contains("boot", &[]));
+        assert!(names.contains("boot-efi", &[]));
+        assert!(names.contains("root", &system));
+        assert!(!names.contains("homes", &[]));
+        assert!(!names.contains("root", &tags(&["systems"])));
+
+        let names = NameFilter::new(&["[ab]?"]).unwrap();
+        assert!(names.contains("a1", &[]));
+        assert!(!names.contains("c1", &[]));
+        assert!(!names.contains("a12", &[]));
+    }
+
+    #[test]
+    fn unmatched() {
+        let system = tags(&["system"]);
+        let volumes = [("home", &[][..]), ("root", &system[..])];
+        let names = NameFilter::new(&["home", "r*", "@system"]).unwrap();
+        names.check(&volumes, "volume").unwrap();
+
+        let names = NameFilter::new(&["hom", "x*", "@system", "@data"]).unwrap();
+        let err = names.check(&volumes, "volume").unwrap_err();
+        assert_eq!(err.to_string(), "No volume matches: hom, x*, @data");
+    }
+
+    #[test]
+    fn invalid_glob() {
+        assert!(NameFilter::new(&["[home"]).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Scheduler daemon.
+//!
+//! For hosts without systemd timers, `rdump daemon` runs the scheduled
+//! profiles itself.  The time each profile last ran is kept in the state
+//! file, and a profile is due at the first time in its schedule after
+//! that.  Because of this, a run missed while the host was suspended or
+//! powered off is made as soon as the daemon next looks, although only
+//! once, however many were missed.  The daemon checks the wall clock
+//! frequently, rather than sleeping for the whole wait, so that it notices
+//! when a suspend has made a run due.
+//!
+//! Each run is also remembered by the daemon itself, so that a run that
+//! fails before it can be recorded, such as when the state file can't be
+//! written, is next tried at the following time in its schedule, rather
+//! than straight away.
+//!
+//! A SIGHUP reloads the config.  SIGINT or SIGTERM stop the daemon, after
+//! cancelling any run in progress, and performing its cleanups.
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local, Utc};
+use log::{error, info};
+use signal_hook::{consts::SIGHUP, flag};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    cancel,
+    config::ConfigFile,
+    lock::{self, Lock},
+    period::Period,
+    state::State,
+    systemd::Timer,
+};
+
+/// Run the scheduled profiles of the config at `path`, until stopped.
+/// `config` is the config already loaded from it.
+pub fn run(path: &Path, mut config: ConfigFile) -> Result<()> {
+    // Only one daemon should run, which has its own lock, separate from
+    // the one held during each run.
+    let _daemon = Lock::acquire(lock::with_suffix(Path::new(config.lock_path()), ".daemon"))?;
+
+    cancel::install()?;
+    let reload = Arc::new(AtomicBool::new(false));
+    flag::register(SIGHUP, Arc::clone(&reload))?;
+
+    start(&config)?;
+
+    // The time each profile was last run by this daemon.
+    let mut runs: HashMap<String, DateTime<Utc>> = HashMap::new();
+
+    loop {
+        if cancel::cancelled() {
+            info!("Daemon stopping");
+            return Ok(());
+        }
+        if reload.swap(false, Ordering::SeqCst) {
+            match ConfigFile::load(path) {
+                Ok(c) => {
+                    info!("Reloaded config from {:?}", path);
+                    config = c;
+                    if let Err(e) = start(&config) {
+                        error!("Unable to schedule new profiles: {:#}", e);
+                    }
+                }
+                Err(e) => error!("Unable to reload config, keeping the old one: {:#}", e),
+            }
+        }
+
+        let state = match State::load(config.state_path()) {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Unable to load state, trying again shortly: {:#}", e);
+                sleep_until(Utc::now() + Duration::minutes(1), &reload);
+                continue;
+            }
+        };
+        let now = Utc::now();
+        let mut next: Option<(DateTime<Utc>, String)> = None;
+        for timer in config.timers() {
+            let saved = state.profiles.get(timer.profile).and_then(|p| p.last_run);
+            if saved.is_none() {
+                // Not yet scheduled by `start`, which is tried again on
+                // the next reload, so start from now.
+                runs.entry(timer.profile.to_string()).or_insert(now);
+            }
+            let due = next_due(&timer, saved, runs.get(timer.profile).cloned(), now);
+            let sooner = match next {
+                Some((t, _)) => due < t,
+                None => true,
+            };
+            if sooner {
+                next = Some((due, timer.profile.to_string()));
+            }
+        }
+
+        match next {
+            Some((due, profile)) if due <= now => {
+                runs.insert(profile.clone(), now);
+                if let Err(e) = run_profile(&config, &profile) {
+                    error!("Scheduled run of {:?} failed: {:#}", profile, e);
+                }
+            }
+            Some((due, profile)) => {
+                info!(
+                    "Next run is {:?} at {}",
+                    profile,
+                    due.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+                );
+                sleep_until(due, &reload);
+            }
+            None => {
+                info!("No profiles are scheduled");
+                sleep_until(now + Duration::days(1), &reload);
+            }
+        }
+    }
+}
+
+/// When the profile of `timer` is next due.  `last_run` is when the state
+/// file says it last ran, and `last_attempt` is when this daemon last
+/// started it, which is later if that run failed before it was recorded.
+/// A profile that has run at neither is due at the first time after `now`.
+/// The result may be before `now`, for a run missed while the daemon
+/// wasn't running, or the host was suspended.
+fn next_due(
+    timer: &Timer,
+    last_run: Option<DateTime<Utc>>,
+    last_attempt: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let last = last_run.max(last_attempt).unwrap_or(now);
+    let due = timer.schedule.next_after(last.with_timezone(&Local));
+    due.with_timezone(&Utc) + delay(timer.profile, due, timer.randomized_delay)
+}
+
+/// Schedule any profiles that have never been run by the daemon from now,
+/// rather than running them immediately.
+fn start(config: &ConfigFile) -> Result<()> {
+    State::update(config.state_path(), |state| {
+        for timer in config.timers() {
+            let entry = state.profiles.entry(timer.profile.to_string()).or_default();
+            if entry.last_run.is_none() {
+                entry.last_run = Some(Utc::now());
+            }
+        }
+    })
+}
+
+/// Run a profile, recording the run in the state file.  Waits for any
+/// other run, such as one started by hand, to finish first.
+fn run_profile(config: &ConfigFile, profile: &str) -> Result<()> {
+    let _lock = config.lock(true)?;
+
+    State::update(config.state_path(), |state| {
+        state
+            .profiles
+            .entry(profile.to_string())
+            .or_default()
+            .last_run = Some(Utc::now());
+    })?;
+
+    info!("Starting scheduled run of {:?}", profile);
+    let runner = config.build_runner(&[], Some(profile))?;
+    let summary = runner.run(false);
+    summary.show();
+    summary.check()
+}
+
+/// The random delay to add to a run of the profile at `due`.  This is
+/// derived from both, so it stays the same while waiting for the run.
+fn delay(profile: &str, due: DateTime<Local>, limit: Option<Period>) -> Duration {
+    let limit = match limit {
+        Some(limit) => limit.duration().num_seconds(),
+        None => return Duration::zero(),
+    };
+    if limit <= 0 {
+        return Duration::zero();
+    }
+    let mut hasher = DefaultHasher::new();
+    profile.hash(&mut hasher);
+    due.timestamp().hash(&mut hasher);
+    Duration::seconds((hasher.finish() % limit as u64) as i64)
+}
+
+/// Sleep until the given time, returning early if the daemon is stopped,
+/// or asked to reload.  The clock is checked each second, so that time
+/// spent suspended is noticed.
+fn sleep_until(time: DateTime<Utc>, reload: &AtomicBool) {
+    while Utc::now() < time && !cancel::cancelled() && !reload.load(Ordering::SeqCst) {
+        thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&text.parse().unwrap())
+    }
+
+    /// When a profile with the given schedule is next due.  Schedules of
+    /// quarter hours are the same in any time zone.
+    fn due(
+        schedule: &str,
+        last_run: Option<&str>,
+        last_attempt: Option<&str>,
+        now: &str,
+    ) -> String {
+        let schedule = schedule.parse().unwrap();
+        let timer = Timer {
+            profile: "home",
+            schedule: &schedule,
+            randomized_delay: None,
+        };
+        next_due(&timer, last_run.map(at), last_attempt.map(at), at(now))
+            .format("%Y-%m-%dT%H:%M:%S")
+            .to_string()
+    }
+
+    #[test]
+    fn catch_up() {
+        // The run at 10:15 was missed while down, so is due straight away,
+        // but only once.
+        assert_eq!(
+            due(
+                "*:0/15",
+                Some("2021-06-01T10:07:00"),
+                None,
+                "2021-06-01T15:02:00"
+            ),
+            "2021-06-01T10:15:00"
+        );
+        assert_eq!(
+            due(
+                "*:0/15",
+                Some("2021-06-01T15:02:00"),
+                None,
+                "2021-06-01T15:02:00"
+            ),
+            "2021-06-01T15:15:00"
+        );
+    }
+
+    #[test]
+    fn after_failure() {
+        // A run that failed before it was recorded is next tried at the
+        // following time in the schedule, not straight away.
+        assert_eq!(
+            due(
+                "*:0/15",
+                Some("2021-06-01T10:07:00"),
+                Some("2021-06-01T15:02:00"),
+                "2021-06-01T15:02:01"
+            ),
+            "2021-06-01T15:15:00"
+        );
+        // An older attempt doesn't hold back a recorded run.
+        assert_eq!(
+            due(
+                "*:0/15",
+                Some("2021-06-01T15:20:00"),
+                Some("2021-06-01T15:02:00"),
+                "2021-06-01T15:20:01"
+            ),
+            "2021-06-01T15:30:00"
+        );
+    }
+
+    #[test]
+    fn reload() {
+        // A changed schedule takes effect from the last run.
+        let last = Some("2021-06-01T10:07:00");
+        let now = "2021-06-01T10:08:00";
+        assert_eq!(due("*:0/15", last, None, now), "2021-06-01T10:15:00");
+        assert_eq!(due("*:0/30", last, None, now), "2021-06-01T10:30:00");
+        // A new profile is scheduled from now, rather than run at once.
+        assert_eq!(due("*:0/30", None, None, now), "2021-06-01T10:30:00");
+        assert_eq!(
+            due("*:0/30", None, Some(now), "2021-06-01T10:40:00"),
+            "2021-06-01T10:30:00"
+        );
+    }
+
+    #[test]
+    fn random_delay() {
+        let schedule = "*:0/15".parse().unwrap();
+        let timer = Timer {
+            profile: "home",
+            schedule: &schedule,
+            randomized_delay: Some("10m".parse().unwrap()),
+        };
+        let last = Some(at("2021-06-01T10:07:00"));
+        let first = next_due(&timer, last, None, at("2021-06-01T10:08:00"));
+        let delay = first - at("2021-06-01T10:15:00");
+        assert!(delay >= Duration::zero() && delay < Duration::minutes(10));
+        // The delay stays the same while waiting for the run.
+        assert_eq!(
+            next_due(&timer, last, None, at("2021-06-01T10:14:00")),
+            first
+        );
+    }
+}
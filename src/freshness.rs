@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Backup freshness checks.
+//!
+//! This is intended to be run by a monitoring system, such as nagios or
+//! icinga.  For each volume, it checks how old the last backup, rsure scan,
+//! and ZFS mirror snapshot are, and reports the worst of these as the
+//! exit status.  The output follows the plugin conventions: a single
+//! summary line, followed by details.
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use crate::{actions::Stats, config::Volume, history::Record, period::Period, timestamp, zfs::Zfs};
+
+/// The result of a check, ordered by severity.  The values are the plugin
+/// exit codes.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+/// Age limits for one kind of backup.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub warn: Period,
+    pub crit: Period,
+}
+
+/// Age limits for each kind of backup.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgeLimits {
+    /// The last successful borg backup.
+    pub backup: Limits,
+    /// The surefile written by the rsure scan.
+    pub rsure: Limits,
+    /// The most recent snapshot of the ZFS mirror.
+    pub zfs: Limits,
+}
+
+/// Check the freshness of all of the given volumes, printing a report,
+/// and returning the overall status.  The time of the last backup comes
+/// from the last run in the history that made a borg archive of the
+/// volume, so runs that only prune or mirror it don't count.  For volumes
+/// that aren't in the history at all, the stamp written at the start of
+/// each run is used instead.  As failed runs write the stamp as well, it
+/// isn't used once the history has any runs of the volume, so a volume
+/// with only failed runs is stale.
+pub fn check(volumes: &[Volume], limits: &AgeLimits, history: &[Record]) -> Result<Status> {
+    let zfs = if volumes.iter().any(|v| v.mirror.is_some()) {
+        Some(Zfs::new(None, "")?)
+    } else {
+        None
+    };
+    let now = Utc::now();
+
+    let mut items = vec![];
+    for vol in volumes {
+        let mount = Path::new(vol.mount);
+        let last = if history.iter().any(|r| r.has_volume(vol.name)) {
+            last_backup(vol.name, history)
+        } else {
+            mtime(&mount.join("snapstamp"))
+        };
+        items.push(Item::new(vol.name, "backup", last, &limits.backup, now));
+
+        let sure = mtime(&mount.join("2sure.dat.gz"));
+        items.push(Item::new(vol.name, "rsure", sure, &limits.rsure, now));
+
+        if let (Some(zfs), Some(mirror)) = (&zfs, vol.mirror) {
+            let newest = zfs
+                .filesystems
+                .iter()
+                .find(|fs| fs.name == mirror)
+                .and_then(|fs| fs.snaps.iter().filter_map(|s| timestamp::parse(s)).max());
+            items.push(Item::new(vol.name, "zfs", newest, &limits.zfs, now));
+        }
+    }
+
+    let status = items.iter().map(|i| i.status).max().unwrap_or(Status::Ok);
+    let problems: Vec<_> = items
+        .iter()
+        .filter(|i| i.status != Status::Ok)
+        .map(|i| format!("{} {} {}", i.volume, i.kind, i.describe()))
+        .collect();
+    if problems.is_empty() {
+        println!("RDUMP {} - {} volumes fresh", status.name(), volumes.len());
+    } else {
+        println!("RDUMP {} - {}", status.name(), problems.join(", "));
+    }
+    for item in &items {
+        println!(
+            "{}: {} {} {}",
+            item.status.name(),
+            item.volume,
+            item.kind,
+            item.describe()
+        );
+    }
+
+    Ok(status)
+}
+
+/// When the last run to make a borg archive of the volume finished.
+fn last_backup(volume: &str, history: &[Record]) -> Option<DateTime<Utc>> {
+    history
+        .iter()
+        .filter(|r| r.stats(volume).any(|s| matches!(s, Stats::Borg(_))))
+        .map(|r| r.summary.end)
+        .max()
+}
+
+/// A single thing that was checked.
+struct Item<'a> {
+    volume: &'a str,
+    kind: &'static str,
+    age: Option<Duration>,
+    status: Status,
+}
+
+impl<'a> Item<'a> {
+    fn new(
+        volume: &'a str,
+        kind: &'static str,
+        time: Option<DateTime<Utc>>,
+        limits: &Limits,
+        now: DateTime<Utc>,
+    ) -> Item<'a> {
+        let age = time.map(|t| now - t);
+        let status = match age {
+            None => Status::Critical,
+            Some(age) if age > limits.crit.duration() => Status::Critical,
+            Some(age) if age > limits.warn.duration() => Status::Warning,
+            Some(_) => Status::Ok,
+        };
+        Item {
+            volume,
+            kind,
+            age,
+            status,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.age {
+            None => "missing".to_string(),
+            Some(age) if age.num_days() > 0 => {
+                format!("{}d {}h old", age.num_days(), age.num_hours() % 24)
+            }
+            Some(age) => format!("{}h {}m old", age.num_hours(), age.num_minutes() % 60),
+        }
+    }
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Warning => "WARNING",
+            Status::Critical => "CRITICAL",
+            Status::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            warn: Period::from_duration(Duration::days(2)),
+            crit: Period::from_duration(Duration::days(4)),
+        }
+    }
+}
+
+/// The modification time of a file, if it exists.
+fn mtime(path: &Path) -> Option<DateTime<Utc>> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .map(DateTime::<Utc>::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::{ActionResult, BorgStats, Summary};
+
+    /// A run of the volume that ended `days` ago, with the given results.
+    fn run(days: i64, results: Vec<ActionResult>) -> Record {
+        let end = Utc::now() - Duration::days(days);
+        Record {
+            id: format!("run-{}", days),
+            host: "host".into(),
+            snapshots: vec![],
+            summary: Summary {
+                start: end - Duration::hours(1),
+                end,
+                volumes: vec!["home".into()],
+                results,
+                ..Summary::default()
+            },
+        }
+    }
+
+    fn result(stats: Option<Stats>, error: Option<&str>) -> ActionResult {
+        ActionResult {
+            volume: Some("home".into()),
+            description: "action".into(),
+            duration: 1.0,
+            stats,
+            error: error.map(|e| e.into()),
+        }
+    }
+
+    fn borg() -> Option<Stats> {
+        Some(Stats::Borg(BorgStats {
+            archive: "home-2021".into(),
+            original_size: 100,
+            compressed_size: 50,
+            deduplicated_size: 10,
+            nfiles: 3,
+            duration: 1.0,
+        }))
+    }
+
+    #[test]
+    fn only_borg_runs_count() {
+        let history = vec![
+            run(10, vec![result(borg(), None)]),
+            run(5, vec![result(borg(), Some("borg failed"))]),
+            // A prune, or an rsync-only profile, leaves no borg archive.
+            run(1, vec![result(None, None)]),
+            run(
+                0,
+                vec![result(Some(Stats::Snapshot("pool/home@1".into())), None)],
+            ),
+        ];
+        assert_eq!(last_backup("home", &history), Some(history[0].summary.end));
+        assert_eq!(last_backup("other", &history), None);
+        assert_eq!(last_backup("home", &history[1..]), None);
+    }
+
+    #[test]
+    fn stale_despite_prunes() {
+        let limits = AgeLimits::default();
+        let status = |history: &[Record]| {
+            let last = last_backup("home", history);
+            Item::new("home", "backup", last, &limits.backup, Utc::now()).status
+        };
+        let fresh = vec![run(0, vec![result(borg(), None)])];
+        let pruned = vec![run(10, vec![result(borg(), None)]), run(0, vec![])];
+        assert_eq!(status(&fresh), Status::Ok);
+        assert_eq!(status(&pruned), Status::Critical);
+    }
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Run history.
+//!
+//! Each run is recorded as a single line of json appended to the history
+//! file.  Appending keeps a crash from damaging earlier records, and the
+//! file is easy to inspect with ordinary tools.
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    actions::{Event, Observer, Stats, Summary},
+    timestamp,
+    zfs::humanize_size,
+};
+
+/// The default location of the history file.
+pub static DEFAULT_HISTORY: &str = "/var/lib/rdump/history.jsonl";
+
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+/// The record of a single run.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Record {
+    /// A unique identifier for the run.
+    pub id: String,
+    /// The host the run was made on.
+    pub host: String,
+    /// The snapshots made during the run.
+    pub snapshots: Vec<String>,
+    #[serde(flatten)]
+    pub summary: Summary,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(path: P) -> History {
+        History {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// Append a record to the history.
+    pub fn append(&self, record: &Record) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Load all of the records from the history, oldest first.  A missing
+    /// history is just empty.  Lines that can't be decoded (such as one
+    /// partially written during a crash) are skipped with a warning.
+    pub fn load(&self) -> Result<Vec<Record>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut result = vec![];
+        for (num, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(record) => result.push(record),
+                Err(e) => warn!("{:?}:{}: invalid history: {}", self.path, num + 1, e),
+            }
+        }
+        Ok(result)
+    }
+}
+
+// The history observer records each run as it finishes.
+impl Observer for History {
+    fn notify(&mut self, event: &Event) {
+        if let Event::RunEnd { summary } = *event {
+            if let Err(err) = self.append(&Record::new(summary)) {
+                log::error!("Unable to record history: {:?}", err);
+            }
+        }
+    }
+}
+
+impl Record {
+    pub fn new(summary: &Summary) -> Record {
+        Record {
+            id: format!(
+                "{}-{}",
+                summary.start.format(timestamp::FORMAT),
+                process::id()
+            ),
+            host: hostname(),
+            snapshots: summary.snapshots(),
+            summary: summary.clone(),
+        }
+    }
+
+    /// Does this run include the given volume?
+    pub fn has_volume(&self, volume: &str) -> bool {
+        self.summary.volumes.iter().any(|v| v == volume)
+    }
+
+    /// The statistics of the actions for the given volume that succeeded.
+    /// These show what the run did for the volume, such as making a borg
+    /// archive, as opposed to only pruning or mirroring it.
+    pub fn stats<'a>(&'a self, volume: &'a str) -> impl Iterator<Item = &'a Stats> + 'a {
+        self.summary
+            .results
+            .iter()
+            .filter(move |r| r.volume.as_deref() == Some(volume) && r.error.is_none())
+            .filter_map(|r| r.stats.as_ref())
+    }
+}
+
+/// Print the given records.  If a volume is given, the actions performed
+/// for that volume are shown as well.
+pub fn show(records: &[&Record], volume: Option<&str>) {
+    for rec in records {
+        let elapsed = rec.summary.end - rec.summary.start;
+        println!(
+            "{}  {}  {}  {:>4}m  {:<6}  {}",
+            rec.id,
+            rec.host,
+            rec.summary.start.format("%Y-%m-%d %H:%M:%S"),
+            elapsed.num_minutes(),
+            if rec.summary.success() {
+                "ok"
+            } else {
+                "FAILED"
+            },
+            rec.summary.volumes.join(", ")
+        );
+        if let Some(ref err) = rec.summary.error {
+            println!("    error: {}", err);
+        }
+        for err in &rec.summary.cleanup_errors {
+            println!("    cleanup error: {}", err);
+        }
+
+        if let Some(volume) = volume {
+            for res in &rec.summary.results {
+                if res.volume.as_deref() != Some(volume) {
+                    continue;
+                }
+                let extra = match res.stats {
+                    Some(Stats::Borg(ref st)) => {
+                        format!(
+                            " ({} new)",
+                            humanize_size(st.deduplicated_size as usize).trim()
+                        )
+                    }
+                    Some(Stats::Rsure(ref st)) => {
+                        format!(" ({} files, {} changed)", st.files, st.changed)
+                    }
+                    _ => String::new(),
+                };
+                println!(
+                    "    {:>8.1}s  {}  {}{}",
+                    res.duration,
+                    if res.error.is_none() {
+                        "ok    "
+                    } else {
+                        "FAILED"
+                    },
+                    res.description,
+                    extra
+                );
+            }
+        }
+    }
+}
+
+/// The name of this host.
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::{ActionResult, BorgStats};
+    use std::env;
+
+    /// A history in a file of its own, removed when done.
+    struct TestHistory(History);
+
+    impl TestHistory {
+        fn new(name: &str) -> TestHistory {
+            let path = env::temp_dir()
+                .join(format!("rdump-test-{}", process::id()))
+                .join(format!("{}.jsonl", name));
+            let _ = fs::remove_file(&path);
+            TestHistory(History::new(path))
+        }
+    }
+
+    impl Drop for TestHistory {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0.path);
+        }
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            volumes: vec!["home".into()],
+            results: vec![ActionResult {
+                volume: Some("home".into()),
+                description: "Borg backup of home".into(),
+                duration: 12.5,
+                stats: Some(Stats::Borg(BorgStats {
+                    archive: "home-20210601T120000".into(),
+                    original_size: 1000,
+                    compressed_size: 500,
+                    deduplicated_size: 100,
+                    nfiles: 3,
+                    duration: 12.0,
+                })),
+                error: None,
+            }],
+            error: Some("rsync failed".into()),
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn missing_is_empty() {
+        let history = TestHistory::new("missing");
+        assert!(history.0.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let history = TestHistory::new("round-trip");
+        let first = Record::new(&summary());
+        let second = Record::new(&Summary::default());
+        history.0.append(&first).unwrap();
+        history.0.append(&second).unwrap();
+
+        let records = history.0.load().unwrap();
+        assert_eq!(records.len(), 2);
+        let rec = &records[0];
+        assert_eq!(rec.id, first.id);
+        assert_eq!(rec.host, first.host);
+        assert_eq!(rec.summary.start, first.summary.start);
+        assert_eq!(rec.summary.error.as_deref(), Some("rsync failed"));
+        assert!(rec.has_volume("home"));
+        assert!(!rec.has_volume("root"));
+        match rec.stats("home").collect::<Vec<_>>()[..] {
+            [Stats::Borg(st)] => {
+                assert_eq!(st.archive, "home-20210601T120000");
+                assert_eq!(st.deduplicated_size, 100);
+            }
+            ref other => panic!("Unexpected stats: {:?}", other),
+        }
+        assert!(records[1].summary.volumes.is_empty());
+    }
+
+    #[test]
+    fn skip_corrupt() {
+        let history = TestHistory::new("corrupt");
+        history.0.append(&Record::new(&summary())).unwrap();
+
+        // A record cut short by a crash, followed by later runs.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&history.0.path)
+            .unwrap();
+        file.write_all(b"{\"id\":\"partial\",\"host\":\n").unwrap();
+        history.0.append(&Record::new(&summary())).unwrap();
+
+        let records = history.0.load().unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.id != "partial"));
+    }
+}
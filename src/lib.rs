@@ -6,10 +6,29 @@ pub use anyhow::Result;
 pub use checked::CheckedExt;
 pub use config::ConfigFile;
 pub use sudo::Sudo;
+pub use timestamp::parse_user as parse_time;
 pub use zfs::Zfs;
 
 pub mod actions;
+mod borg;
+pub mod cancel;
+pub mod catalog;
 mod checked;
 pub mod config;
+pub mod daemon;
+pub mod freshness;
+pub mod history;
+pub mod lock;
+mod maintain;
+mod metrics;
+mod notify;
+pub mod period;
+mod restore;
+pub mod retention;
+pub mod schedule;
+mod state;
 mod sudo;
+mod surefile;
+pub mod systemd;
+mod timestamp;
 mod zfs;
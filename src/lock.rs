@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Single instance lock.
+//!
+//! Only one backup run should happen at a time, whether started by hand,
+//! by a timer, or by `rdump daemon`.  Each run holds an exclusive lock on
+//! the lock file while it runs.  The lock is released by the kernel when
+//! the process exits, however it exits, so there is never a stale lock to
+//! clean up.  The lock file holds the pid of the holder, for messages.
+
+use anyhow::{anyhow, Result};
+use log::info;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use crate::cancel;
+
+/// The default location of the lock file.
+pub static DEFAULT_LOCK: &str = "/run/lock/rdump.lock";
+
+/// A held lock.  The lock is released when this is dropped.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock, failing if another run holds it.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Lock> {
+        let path = path.as_ref();
+        match Lock::try_acquire(path)? {
+            Some(lock) => Ok(lock),
+            None => Err(anyhow!(
+                "Another rdump run is in progress ({})",
+                Lock::holder(path)
+            )),
+        }
+    }
+
+    /// Take the lock, waiting for any other run to finish first.  Fails
+    /// if the wait is cancelled, or takes longer than `timeout`.
+    pub fn wait<P: AsRef<Path>>(path: P, timeout: Option<Duration>) -> Result<Lock> {
+        let path = path.as_ref();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut logged = false;
+        loop {
+            if let Some(lock) = Lock::try_acquire(path)? {
+                return Ok(lock);
+            }
+            if !logged {
+                info!("Waiting for other rdump run ({})", Lock::holder(path));
+                logged = true;
+            }
+            if cancel::cancelled() {
+                return Err(anyhow!("Cancelled"));
+            }
+            if deadline.map_or(false, |d| Instant::now() >= d) {
+                return Err(anyhow!(
+                    "Timed out waiting for other rdump run ({})",
+                    Lock::holder(path)
+                ));
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    fn try_acquire(path: &Path) -> Result<Option<Lock>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| anyhow!("Unable to open lock file {:?}: {}", path, e))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(anyhow!("Unable to lock {:?}: {}", path, err));
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(Some(Lock { _file: file }))
+    }
+
+    /// Describe the holder of the lock, from the pid it wrote.
+    fn holder(path: &Path) -> String {
+        let mut text = String::new();
+        let _ = File::open(path).and_then(|mut f| f.read_to_string(&mut text));
+        match text.trim() {
+            "" => format!("lock {:?}", path),
+            pid => format!("pid {}, lock {:?}", pid, path),
+        }
+    }
+}
+
+/// The path of a lock, with a suffix added, for a separate lock.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A lock file in a directory of its own, for each test.
+    fn path(test: &str) -> PathBuf {
+        env::temp_dir()
+            .join(format!("rdump-test-{}-{}", process::id(), test))
+            .join("rdump.lock")
+    }
+
+    #[test]
+    fn held() {
+        let path = path("held");
+        let lock = Lock::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        let err = Lock::acquire(&path).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Another rdump run is in progress (pid {}, lock {:?})",
+                process::id(),
+                path
+            )
+        );
+        // A separate lock can still be taken.
+        let other = Lock::acquire(with_suffix(&path, ".daemon")).unwrap();
+
+        drop(lock);
+        drop(Lock::acquire(&path).unwrap());
+        drop(other);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wait() {
+        let _serial = cancel::serial();
+        let path = path("wait");
+        let lock = Lock::wait(&path, None).unwrap();
+
+        let start = Instant::now();
+        let err = Lock::wait(&path, Some(Duration::from_secs(1)))
+            .err()
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(
+            err.to_string()
+                .starts_with("Timed out waiting for other rdump run (pid "),
+            "{}",
+            err
+        );
+
+        cancel::set_cancelled(true);
+        let cancelled = Lock::wait(&path, None).err().map(|e| e.to_string());
+        cancel::set_cancelled(false);
+        assert_eq!(cancelled.as_deref(), Some("Cancelled"));
+
+        drop(lock);
+        drop(Lock::wait(&path, Some(Duration::from_secs(1))).unwrap());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -3,14 +3,12 @@
 
 use anyhow::Result;
 use clap::{load_yaml, App};
-use rdump::{ConfigFile, Zfs};
-use std::{fs, path::Path, thread};
+use rdump::{
+    cancel, catalog, daemon, freshness::Status, history, period::Period, systemd, ConfigFile,
+};
+use std::{io, path::Path, process};
 
 fn main() -> Result<()> {
-    if false {
-        wasy()?;
-    }
-
     rsure::log_init();
 
     let yaml = load_yaml!("cli.yaml");
@@ -21,16 +19,38 @@ fn main() -> Result<()> {
     let cname = matches.value_of("config").unwrap_or("rdump.yaml");
     // println!("cname: {:?}", cname);
 
+    // Checking the config reports all of the problems with it, so must
+    // be done before the usual load.
+    if matches.subcommand_matches("check-config").is_some() {
+        let problems = ConfigFile::check(&cname)?;
+        for problem in &problems {
+            println!("{}", problem.located(cname));
+        }
+        if !problems.is_empty() {
+            println!("{} problem(s) found", problems.len());
+            process::exit(1);
+        }
+        println!("{}: ok", cname);
+        return Ok(());
+    }
+
+    // The freshness check is run by monitoring, which expects nothing
+    // but the report, and the status in the exit code, even when the
+    // config or history can't be loaded.
+    if matches.subcommand_matches("check-freshness").is_some() {
+        let status = match ConfigFile::load(&cname).and_then(|c| c.check_freshness()) {
+            Ok(status) => status,
+            Err(e) => {
+                println!("RDUMP UNKNOWN - {:#}", e);
+                Status::Unknown
+            }
+        };
+        process::exit(status as i32);
+    }
+
     let config = ConfigFile::load(&cname)?;
-    println!("Config: {:#?}", config);
 
     if let Some(matches) = matches.subcommand_matches("clone") {
-        let volume = matches.value_of("VOLUME").unwrap();
-        let _sudo = rdump::Sudo::start(true)?;
-        println!("volume: {:?}", volume);
-        println!("Sleeping 1 minute");
-        thread::sleep(std::time::Duration::from_secs(60));
-    } else if let Some(matches) = matches.subcommand_matches("backup") {
         let pretend = matches.occurrences_of("pretend") > 0;
 
         let names: Vec<_> = matches
@@ -38,28 +58,113 @@ fn main() -> Result<()> {
             .map(|c| c.collect())
             .unwrap_or(vec![]);
 
-        let runner = config.build_runner(&names)?;
-        runner.run(pretend)?;
-    }
+        let runner = config.build_clone_runner(&names)?;
+        let _sudo = rdump::Sudo::start(true)?;
+        // Pretending changes nothing, so doesn't need to exclude other runs.
+        let _lock = if pretend {
+            None
+        } else {
+            Some(config.lock(matches.occurrences_of("wait") > 0)?)
+        };
+        cancel::install()?;
+        let summary = runner.run(pretend);
+        summary.show();
+        summary.check()?;
+    } else if let Some(matches) = matches.subcommand_matches("prune") {
+        let really = matches.occurrences_of("really") > 0;
 
-    Ok(())
-}
+        let names: Vec<_> = matches
+            .values_of("NAME")
+            .map(|c| c.collect())
+            .unwrap_or(vec![]);
 
-fn wasy() -> Result<()> {
-    let fs = Zfs::new(None, "da2021")?;
-    // println!("{:#?}", fs);
-
-    // Scan each filesystem that has a mountpoint, looking for a
-    // .zfssync.yml file.
-    let mut valid = vec![];
-    for fs in &fs.filesystems {
-        let base = Path::new(&fs.mount);
-        let work = base.join(".zfssync.yml");
-        match fs::metadata(&work) {
-            Ok(_) => valid.push(fs),
-            Err(_) => (),
+        let runner = config.build_prune_runner(&names)?;
+        let _lock = if really {
+            Some(config.lock(matches.occurrences_of("wait") > 0)?)
+        } else {
+            None
+        };
+        cancel::install()?;
+        let summary = runner.run(!really);
+        summary.show();
+        summary.check()?;
+    } else if let Some(matches) = matches.subcommand_matches("maintain") {
+        let pretend = matches.occurrences_of("pretend") > 0;
+        let force = matches.occurrences_of("force") > 0;
+        config.maintain(force, pretend)?;
+    } else if let Some(matches) = matches.subcommand_matches("restore") {
+        let pretend = matches.occurrences_of("pretend") > 0;
+        let name = matches.value_of("NAME").unwrap();
+        let at = match matches.value_of("at") {
+            Some(at) => Some(rdump::parse_time(at)?),
+            None => None,
+        };
+        let path = matches.value_of("path");
+        let target = Path::new(matches.value_of("target").unwrap());
+        config.restore(name, at, path, target, pretend)?;
+    } else if let Some(matches) = matches.subcommand_matches("list") {
+        let names: Vec<_> = matches
+            .values_of("NAME")
+            .map(|c| c.collect())
+            .unwrap_or(vec![]);
+        let max_age: Period = matches.value_of("max-age").unwrap_or("2d").parse()?;
+
+        let catalog = config.catalog(&names, max_age)?;
+        if matches.occurrences_of("json") > 0 {
+            serde_json::to_writer_pretty(io::stdout().lock(), &catalog)?;
+            println!();
+        } else {
+            catalog::show(&catalog);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("history") {
+        let name = matches.value_of("NAME");
+        let count: usize = matches.value_of("count").unwrap_or("10").parse()?;
+        let failures = matches.occurrences_of("failures") > 0;
+
+        let records = config.history().load()?;
+        let mut records: Vec<_> = records
+            .iter()
+            .filter(|r| match name {
+                Some(name) => r.has_volume(name),
+                None => true,
+            })
+            .filter(|r| !failures || !r.summary.success())
+            .collect();
+        let skip = records.len().saturating_sub(count);
+        records.drain(..skip);
+        history::show(&records, name);
+    } else if matches.subcommand_matches("daemon").is_some() {
+        daemon::run(Path::new(cname), config)?;
+    } else if let Some(matches) = matches.subcommand_matches("systemd") {
+        if let Some(matches) = matches.subcommand_matches("generate") {
+            let pretend = matches.occurrences_of("pretend") > 0;
+            let dir = Path::new(matches.value_of("dir").unwrap_or(systemd::DEFAULT_DIR));
+            config.systemd_generate(Path::new(cname), dir, pretend)?;
+        } else if matches.subcommand_matches("status").is_some() {
+            config.systemd_status()?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("backup") {
+        let pretend = matches.occurrences_of("pretend") > 0;
+
+        let names: Vec<_> = matches
+            .values_of("NAME")
+            .map(|c| c.collect())
+            .unwrap_or(vec![]);
+
+        let runner = config.build_runner(&names, matches.value_of("profile"))?;
+        let _lock = if pretend {
+            None
+        } else {
+            Some(config.lock(matches.occurrences_of("wait") > 0)?)
+        };
+        cancel::install()?;
+        let summary = runner.run(pretend);
+        summary.show();
+        if let Some(report) = matches.value_of("report") {
+            summary.write_json(report)?;
         }
+        summary.check()?;
     }
-    println!("Valid: {:?}", valid);
+
     Ok(())
 }
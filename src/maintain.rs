@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Repository maintenance.
+//!
+//! Borg repositories should be checked periodically for consistency, and
+//! compacted to free space from pruned archives.  A full data
+//! verification is expensive, so this is only done when it is due, based
+//! on the times recorded in the state file.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::{borg::Borg, period::Period, state::State};
+
+/// How often the various maintenance operations should be performed.
+pub struct Schedule {
+    /// How often to run `borg check`.  If None, it is run every time.
+    pub check: Option<Period>,
+    /// How often to run `borg check --verify-data`.  If None, data is
+    /// never verified.
+    pub verify_data: Option<Period>,
+}
+
+/// Perform maintenance on each of the given repositories, updating the
+/// state file after each successful step.  With `force`, all operations
+/// are performed, regardless of when they were last done.
+pub fn maintain(
+    repos: &[Borg],
+    schedule: &Schedule,
+    state_path: &str,
+    force: bool,
+    pretend: bool,
+) -> Result<()> {
+    // The state is only read here, and each change is made with
+    // `State::update`, as a run may change the state during maintenance.
+    let state = State::load(state_path)?;
+
+    for borg in repos {
+        let now = Utc::now();
+        let script = borg.script().to_string();
+        let repo = state.repos.get(&script);
+        let last_check = repo.and_then(|r| r.last_check);
+        let last_verify = repo.and_then(|r| r.last_verify);
+
+        let verify = match schedule.verify_data {
+            Some(period) => force || is_due(last_verify, period, now),
+            None => false,
+        };
+        let check = verify
+            || force
+            || match schedule.check {
+                Some(period) => is_due(last_check, period, now),
+                None => true,
+            };
+
+        if check {
+            if pretend {
+                println!(
+                    "would: borg check{} via {}",
+                    if verify { " --verify-data" } else { "" },
+                    borg.script()
+                );
+            } else {
+                borg.check(verify)?;
+                State::update(state_path, |state| {
+                    let repo = state.repos.entry(script.clone()).or_default();
+                    repo.last_check = Some(now);
+                    if verify {
+                        repo.last_verify = Some(now);
+                    }
+                })?;
+            }
+        } else {
+            println!("Borg check of {} not yet due", borg.script());
+        }
+
+        if pretend {
+            println!("would: borg compact via {}", borg.script());
+        } else {
+            borg.compact()?;
+            State::update(state_path, |state| {
+                state.repos.entry(script.clone()).or_default().last_compact = Some(Utc::now());
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Is an operation last performed at `last` due to be run again?
+fn is_due(last: Option<DateTime<Utc>>, period: Period, now: DateTime<Utc>) -> bool {
+    match last {
+        None => true,
+        Some(last) => now - last >= period.duration(),
+    }
+}
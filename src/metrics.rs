@@ -0,0 +1,412 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Prometheus metrics.
+//!
+//! After each run, the metrics are written in the text exposition format,
+//! for the node_exporter textfile collector.  The metrics are built from
+//! the history, so that each volume is described by its most recent run,
+//! even when a run only involves some of the volumes.  The statistics of
+//! each kind come from the most recent run that produced them, so that a
+//! prune, or a profile with only some of the actions, doesn't hide them.
+//! Volumes are labelled with `volume`, and zfs replications with
+//! `replication`.
+
+use anyhow::Result;
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    actions::{Event, Observer, Stats},
+    history::{History, Record},
+};
+
+/// The name of the metrics file within the collector directory.
+static METRICS_FILE: &str = "rdump.prom";
+
+/// An observer that rewrites the metrics at the end of each run.  This
+/// should be added after the history observer, so that the run just
+/// finished is included.
+pub struct MetricsWriter {
+    dir: PathBuf,
+    volumes: Vec<String>,
+    replications: Vec<String>,
+    history: History,
+}
+
+impl MetricsWriter {
+    pub fn new(
+        dir: &Path,
+        volumes: Vec<String>,
+        replications: Vec<String>,
+        history: History,
+    ) -> MetricsWriter {
+        MetricsWriter {
+            dir: dir.to_owned(),
+            volumes,
+            replications,
+            history,
+        }
+    }
+}
+
+impl Observer for MetricsWriter {
+    fn notify(&mut self, event: &Event) {
+        if let Event::RunEnd { .. } = *event {
+            let result = self
+                .history
+                .load()
+                .and_then(|history| write(&self.dir, &self.volumes, &self.replications, &history));
+            if let Err(err) = result {
+                log::error!("Unable to write metrics: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Write the metrics for each of the named volumes and replications to
+/// the given directory.  The file is written under a temporary name and
+/// renamed, so the collector never sees a partial file.
+pub fn write(
+    dir: &Path,
+    volumes: &[String],
+    replications: &[String],
+    history: &[Record],
+) -> Result<()> {
+    let mut metrics = Metrics::new();
+
+    let labelled = volumes
+        .iter()
+        .map(|name| ("volume", name))
+        .chain(replications.iter().map(|name| ("replication", name)));
+    for (label, name) in labelled {
+        let subject = (label, name.as_str());
+        let runs: Vec<_> = history
+            .iter()
+            .rev()
+            .filter(|r| r.has_volume(name))
+            .collect();
+        let last = match runs.first() {
+            Some(last) => last,
+            None => continue,
+        };
+        // The newest stats of each kind, as a run may involve the volume
+        // without backing it up, such as a prune.
+        let newest = |kind: fn(&Stats) -> bool| {
+            runs.iter()
+                .find_map(|r| r.stats(name).find(|s| kind(s)).map(|s| (*r, s)))
+        };
+
+        metrics.add(
+            "last_run_timestamp_seconds",
+            "Time the most recent run involving the volume finished.",
+            subject,
+            last.summary.end.timestamp() as f64,
+        );
+        metrics.add(
+            "last_run_success",
+            "Whether the most recent run involving the volume succeeded.",
+            subject,
+            if last.summary.success() { 1.0 } else { 0.0 },
+        );
+        if let Some((run, _)) = newest(|s| matches!(s, Stats::Borg(_) | Stats::ZfsSend(_))) {
+            metrics.add(
+                "last_success_timestamp_seconds",
+                "Time the most recent successful backup or replication finished.",
+                subject,
+                run.summary.end.timestamp() as f64,
+            );
+        }
+        metrics.add(
+            "duration_seconds",
+            "Time spent on the volume's actions in the most recent run.",
+            subject,
+            last.summary
+                .results
+                .iter()
+                .filter(|r| r.volume.as_deref() == Some(name.as_str()))
+                .map(|r| r.duration)
+                .sum(),
+        );
+
+        if let Some((_, Stats::Borg(st))) = newest(|s| matches!(s, Stats::Borg(_))) {
+            metrics.add(
+                "borg_original_bytes",
+                "Size of the files in the most recent borg archive.",
+                subject,
+                st.original_size as f64,
+            );
+            metrics.add(
+                "borg_compressed_bytes",
+                "Compressed size of the most recent borg archive.",
+                subject,
+                st.compressed_size as f64,
+            );
+            metrics.add(
+                "borg_deduplicated_bytes",
+                "New data added to the repository by the most recent borg archive.",
+                subject,
+                st.deduplicated_size as f64,
+            );
+            metrics.add(
+                "borg_files",
+                "Number of files in the most recent borg archive.",
+                subject,
+                st.nfiles as f64,
+            );
+        }
+        if let Some((_, Stats::Rsure(st))) = newest(|s| matches!(s, Stats::Rsure(_))) {
+            metrics.add(
+                "rsure_files",
+                "Number of files in the most recent rsure scan.",
+                subject,
+                st.files as f64,
+            );
+            metrics.add(
+                "rsure_changed_files",
+                "Number of files added, removed, or changed since the previous rsure scan.",
+                subject,
+                st.changed as f64,
+            );
+        }
+        if let Some((_, Stats::ZfsSend(bytes))) = newest(|s| matches!(s, Stats::ZfsSend(_))) {
+            metrics.add(
+                "zfs_send_bytes",
+                "Estimated size of the most recent ZFS replication.",
+                subject,
+                *bytes as f64,
+            );
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(METRICS_FILE);
+    // The collector only reads files ending in `.prom`.
+    let tmp = dir.join(format!(".{}.tmp", METRICS_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(metrics.render().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// A set of gauges, each with values for multiple volumes.  Kept in the
+/// order first added, so that the output is stable.
+struct Metrics {
+    gauges: Vec<Gauge>,
+}
+
+struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    /// The values, with the label and its value that they are for.
+    values: Vec<(&'static str, String, f64)>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics { gauges: vec![] }
+    }
+
+    fn add(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        (label, subject): (&'static str, &str),
+        value: f64,
+    ) {
+        let pos = match self.gauges.iter().position(|g| g.name == name) {
+            Some(pos) => pos,
+            None => {
+                self.gauges.push(Gauge {
+                    name,
+                    help,
+                    values: vec![],
+                });
+                self.gauges.len() - 1
+            }
+        };
+        self.gauges[pos]
+            .values
+            .push((label, subject.to_string(), value));
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for gauge in &self.gauges {
+            let _ = writeln!(out, "# HELP rdump_{} {}", gauge.name, gauge.help);
+            let _ = writeln!(out, "# TYPE rdump_{} gauge", gauge.name);
+            for (label, subject, value) in &gauge.values {
+                let _ = writeln!(
+                    out,
+                    "rdump_{}{{{}=\"{}\"}} {}",
+                    gauge.name,
+                    label,
+                    escape(subject),
+                    value
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Escape a label value.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::{ActionResult, BorgStats, RsureStats, Summary};
+    use chrono::{TimeZone, Utc};
+    use std::{env, process};
+
+    /// A run of the given volumes that finished at `end` seconds, with the
+    /// results of some of its actions.
+    fn run(end: i64, volumes: &[&str], results: Vec<ActionResult>) -> Record {
+        let end = Utc.timestamp_opt(end, 0).unwrap();
+        Record {
+            id: format!("run-{}", end.timestamp()),
+            host: "host".into(),
+            snapshots: vec![],
+            summary: Summary {
+                start: end - chrono::Duration::seconds(100),
+                end,
+                volumes: volumes.iter().map(|v| v.to_string()).collect(),
+                results,
+                ..Summary::default()
+            },
+        }
+    }
+
+    fn result(volume: &str, duration: f64, stats: Option<Stats>) -> ActionResult {
+        ActionResult {
+            volume: Some(volume.into()),
+            description: "action".into(),
+            duration,
+            stats,
+            error: None,
+        }
+    }
+
+    fn borg(deduplicated_size: u64) -> Option<Stats> {
+        Some(Stats::Borg(BorgStats {
+            archive: "home-20210601T120000".into(),
+            original_size: 1000,
+            compressed_size: 500,
+            deduplicated_size,
+            nfiles: 3,
+            duration: 1.0,
+        }))
+    }
+
+    fn rsure(changed: u64) -> Option<Stats> {
+        Some(Stats::Rsure(RsureStats { files: 10, changed }))
+    }
+
+    /// Write the metrics for the history, and return the lines with values.
+    fn metrics(volumes: &[&str], replications: &[&str], history: &[Record]) -> Vec<String> {
+        let dir = env::temp_dir().join(format!("rdump-test-{}-metrics", process::id()));
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        write(&dir, &names(volumes), &names(replications), history).unwrap();
+        let text = fs::read_to_string(dir.join(METRICS_FILE)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        text.lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn newest_of_each_kind() {
+        let history = vec![
+            run(
+                1000,
+                &["home"],
+                vec![
+                    result("home", 1.0, rsure(4)),
+                    result("home", 2.0, borg(100)),
+                ],
+            ),
+            run(2000, &["home"], vec![result("home", 3.0, rsure(5))]),
+            // A prune, with no stats, and a run of another volume.
+            run(3000, &["home"], vec![result("home", 4.0, None)]),
+            run(4000, &["root"], vec![result("root", 5.0, None)]),
+        ];
+        assert_eq!(
+            metrics(&["home", "boot"], &[], &history),
+            vec![
+                "rdump_last_run_timestamp_seconds{volume=\"home\"} 3000",
+                "rdump_last_run_success{volume=\"home\"} 1",
+                "rdump_last_success_timestamp_seconds{volume=\"home\"} 1000",
+                "rdump_duration_seconds{volume=\"home\"} 4",
+                "rdump_borg_original_bytes{volume=\"home\"} 1000",
+                "rdump_borg_compressed_bytes{volume=\"home\"} 500",
+                "rdump_borg_deduplicated_bytes{volume=\"home\"} 100",
+                "rdump_borg_files{volume=\"home\"} 3",
+                "rdump_rsure_files{volume=\"home\"} 10",
+                "rdump_rsure_changed_files{volume=\"home\"} 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_and_replications() {
+        let mut failed = run(
+            2000,
+            &["home", "media"],
+            vec![
+                result("home", 1.0, borg(200)),
+                result("media", 6.0, Some(Stats::ZfsSend(4096))),
+            ],
+        );
+        failed.summary.results[0].error = Some("borg failed".into());
+        failed.summary.error = Some("borg failed".into());
+        let history = vec![
+            run(1000, &["home"], vec![result("home", 1.0, borg(100))]),
+            failed,
+        ];
+        assert_eq!(
+            metrics(&["home"], &["media"], &history),
+            vec![
+                "rdump_last_run_timestamp_seconds{volume=\"home\"} 2000",
+                "rdump_last_run_timestamp_seconds{replication=\"media\"} 2000",
+                "rdump_last_run_success{volume=\"home\"} 0",
+                "rdump_last_run_success{replication=\"media\"} 0",
+                "rdump_last_success_timestamp_seconds{volume=\"home\"} 1000",
+                "rdump_last_success_timestamp_seconds{replication=\"media\"} 2000",
+                "rdump_duration_seconds{volume=\"home\"} 1",
+                "rdump_duration_seconds{replication=\"media\"} 6",
+                "rdump_borg_original_bytes{volume=\"home\"} 1000",
+                "rdump_borg_compressed_bytes{volume=\"home\"} 500",
+                "rdump_borg_deduplicated_bytes{volume=\"home\"} 100",
+                "rdump_borg_files{volume=\"home\"} 3",
+                "rdump_zfs_send_bytes{replication=\"media\"} 4096",
+            ]
+        );
+    }
+
+    #[test]
+    fn render() {
+        let mut metrics = Metrics::new();
+        metrics.add("files", "Number of files.", ("volume", "home"), 3.0);
+        metrics.add("bytes", "Number of bytes.", ("volume", "home"), 1.5);
+        metrics.add("files", "Number of files.", ("volume", "a\"b\\c\nd"), 4.0);
+        assert_eq!(
+            metrics.render(),
+            "# HELP rdump_files Number of files.\n\
+             # TYPE rdump_files gauge\n\
+             rdump_files{volume=\"home\"} 3\n\
+             rdump_files{volume=\"a\\\"b\\\\c\\nd\"} 4\n\
+             # HELP rdump_bytes Number of bytes.\n\
+             # TYPE rdump_bytes gauge\n\
+             rdump_bytes{volume=\"home\"} 1.5\n"
+        );
+    }
+}
@@ -0,0 +1,589 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Notifications.
+//!
+//! At the end of a run, a summary can be mailed out, by piping it to a
+//! local sendmail-compatible program, and/or posted to a webhook, such as
+//! those provided by Matrix, Slack or ntfy.  Webhooks are posted with
+//! `curl`, which must be installed to use them.
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::{
+    actions::{Event, Observer, Summary},
+    history::{self, History, Record},
+    period::Period,
+};
+
+static CURL: &str = "curl";
+
+/// When to send a notification.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum When {
+    /// After every run.
+    Always,
+    /// Only after runs that fail.
+    Failure,
+    /// Only when the outcome differs from the previous run.
+    Change,
+}
+
+/// Configuration for mailed notifications.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mail {
+    /// The sendmail-compatible program to run.
+    #[serde(default = "default_sendmail")]
+    pub sendmail: String,
+    /// The recipients.
+    pub to: Vec<String>,
+    /// The sender.  Left to sendmail if not given.
+    pub from: Option<String>,
+    #[serde(default = "default_when")]
+    pub when: When,
+}
+
+/// Configuration for webhook notifications.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    /// The body to post.  Within the template, `{{host}}`, `{{subject}}`,
+    /// `{{outcome}}`, `{{volumes}}` and `{{report}}` are replaced with
+    /// the text escaped as the contents of a json string, and `{{summary}}`
+    /// is replaced with the summary as a json object.  If not given, the
+    /// summary itself is posted.
+    pub template: Option<String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// How many times to try posting.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// How long to wait between attempts.
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: Period,
+    /// How long to wait for each attempt.
+    #[serde(default = "default_timeout")]
+    pub timeout: Period,
+    #[serde(default = "default_when")]
+    pub when: When,
+}
+
+/// An observer that sends the configured notifications at the end of each
+/// run.  This should be added after the history observer, as the history
+/// is used to find the previous run.
+pub struct Notifier {
+    mail: Option<Mail>,
+    webhook: Option<Webhook>,
+    history: History,
+}
+
+impl Notifier {
+    pub fn new(mail: Option<Mail>, webhook: Option<Webhook>, history: History) -> Notifier {
+        Notifier {
+            mail,
+            webhook,
+            history,
+        }
+    }
+
+    /// Send each of the wanted notifications.  All are attempted, even if
+    /// an earlier one fails.  A history that can't be read is treated as
+    /// empty, rather than losing the notification.
+    fn send(&self, summary: &Summary) -> Result<()> {
+        let history = match self.history.load() {
+            Ok(history) => history,
+            Err(err) => {
+                warn!("Unable to load history: {:#}", err);
+                vec![]
+            }
+        };
+        let previous = previous(&history, summary);
+        let host = history::hostname();
+
+        let mut result = Ok(());
+        if let Some(ref mail) = self.mail {
+            if mail.when.wanted(summary, previous) {
+                result = result.and(mail.send(summary, &host));
+            }
+        }
+        if let Some(ref webhook) = self.webhook {
+            if webhook.when.wanted(summary, previous) {
+                result = result.and(webhook.send(summary, &host));
+            }
+        }
+        result
+    }
+}
+
+impl Observer for Notifier {
+    fn notify(&mut self, event: &Event) {
+        if let Event::RunEnd { summary } = *event {
+            if let Err(err) = self.send(summary) {
+                log::error!("Unable to send notification: {:?}", err);
+            }
+        }
+    }
+}
+
+impl When {
+    /// Should a notification be sent about this run?  `previous` is the
+    /// most recent earlier run of any of the same volumes.
+    pub fn wanted(self, summary: &Summary, previous: Option<&Record>) -> bool {
+        match self {
+            When::Always => true,
+            When::Failure => !summary.success(),
+            When::Change => match previous {
+                Some(prev) => prev.summary.success() != summary.success(),
+                None => !summary.success(),
+            },
+        }
+    }
+}
+
+impl Mail {
+    /// Mail a report of this run.
+    pub fn send(&self, summary: &Summary, host: &str) -> Result<()> {
+        info!("Mailing run summary to {}", self.to.join(", "));
+
+        let mut message = String::new();
+        if let Some(ref from) = self.from {
+            message.push_str(&format!("From: {}\n", from));
+        }
+        message.push_str(&format!("To: {}\n", self.to.join(", ")));
+        message.push_str(&format!("Subject: {}\n", subject(summary, host)));
+        message.push('\n');
+        message.push_str(&summary.report());
+
+        // With `-t`, the recipients come from the headers, and `-oi` keeps
+        // a line with a single '.' from ending the message.
+        let mut child = Command::new(&self.sendmail)
+            .args(["-t", "-oi"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .expect("Child input")
+            .write_all(message.as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("Error running {} ({:?})", self.sendmail, status));
+        }
+        Ok(())
+    }
+}
+
+impl Webhook {
+    /// Post a report of this run, retrying on failure.
+    pub fn send(&self, summary: &Summary, host: &str) -> Result<()> {
+        let body = self.body(summary, host)?;
+
+        let mut attempt = 1;
+        loop {
+            info!("Posting run summary to {} (attempt {})", self.url, attempt);
+            match self.post(&body) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.attempts => {
+                    warn!("Webhook failed: {:#}", e);
+                    thread::sleep(self.retry_delay.duration().to_std()?);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Build the body to post, from the template.
+    fn body(&self, summary: &Summary, host: &str) -> Result<String> {
+        let json = serde_json::to_string(summary)?;
+        let template = match self.template {
+            Some(ref template) => template,
+            None => return Ok(json),
+        };
+
+        let outcome = if summary.success() { "ok" } else { "failed" };
+        let vars = [
+            ("host", escape_json(host)),
+            ("subject", escape_json(&subject(summary, host))),
+            ("outcome", outcome.to_string()),
+            ("volumes", escape_json(&summary.volumes.join(", "))),
+            ("report", escape_json(&summary.report())),
+            ("summary", json),
+        ];
+        let mut body = template.clone();
+        for (name, value) in &vars {
+            body = body.replace(&format!("{{{{{}}}}}", name), value);
+        }
+        Ok(body)
+    }
+
+    /// Make a single attempt at posting the body.
+    fn post(&self, body: &str) -> Result<()> {
+        // The url, which may contain a token, and the body are given to
+        // curl as a config on its input, rather than as arguments that any
+        // user could see.
+        let config = format!(
+            "url = {}\nheader = {}\ndata-raw = {}\n",
+            quote_curl(&self.url),
+            quote_curl(&format!("Content-Type: {}", self.content_type)),
+            quote_curl(body)
+        );
+
+        // `-f` makes HTTP errors into failures.
+        let mut child = Command::new(CURL)
+            .args(["-sS", "-f", "-o", "/dev/null", "-X", "POST"])
+            .arg("--max-time")
+            .arg(self.max_time().to_string())
+            .args(["-K", "-"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| anyhow!("Unable to run {}: {}", CURL, e))?;
+        child
+            .stdin
+            .take()
+            .expect("Child input")
+            .write_all(config.as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("Error posting to {} ({:?})", self.url, status));
+        }
+        Ok(())
+    }
+
+    /// The timeout for curl, which only takes whole seconds, and treats 0
+    /// as no timeout.
+    fn max_time(&self) -> i64 {
+        let millis = self.timeout.duration().num_milliseconds();
+        ((millis + 999) / 1000).max(1)
+    }
+}
+
+/// A one line description of the run.
+pub fn subject(summary: &Summary, host: &str) -> String {
+    format!(
+        "rdump {}: {} {}",
+        host,
+        summary.volumes.join(", "),
+        if summary.success() { "ok" } else { "FAILED" }
+    )
+}
+
+/// Find the most recent run that started before the run being notified
+/// about, and involved any of the same volumes.  The run itself may or may
+/// not have been recorded in the history.
+pub fn previous<'a>(history: &'a [Record], summary: &Summary) -> Option<&'a Record> {
+    history
+        .iter()
+        .filter(|r| r.summary.start < summary.start)
+        .filter(|r| summary.volumes.iter().any(|v| r.has_volume(v)))
+        .max_by_key(|r| r.summary.start)
+}
+
+fn default_sendmail() -> String {
+    "/usr/sbin/sendmail".into()
+}
+
+fn default_when() -> When {
+    When::Failure
+}
+
+fn default_content_type() -> String {
+    "application/json".into()
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_retry_delay() -> Period {
+    "30s".parse().expect("Valid period")
+}
+
+fn default_timeout() -> Period {
+    "30s".parse().expect("Valid period")
+}
+
+/// Quote a value for a curl config file.
+fn quote_curl(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\x0b' => quoted.push_str("\\v"),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Escape text to be placed within a json string.
+fn escape_json(text: &str) -> String {
+    let quoted = serde_json::to_string(text).expect("String to json");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp;
+    use chrono::Duration;
+    use std::{
+        env, fs,
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        process,
+        thread::JoinHandle,
+    };
+
+    /// A request received by the stand-in server: the request line and
+    /// headers, and the body.
+    type Request = (String, String);
+
+    /// Serve a request on a local port for each of the given statuses,
+    /// answering with that status.  Returns the url to post to, and the
+    /// requests received.
+    fn serve(statuses: Vec<u16>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/hook?token=secret",
+            listener.local_addr().unwrap()
+        );
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                requests.push((head, String::from_utf8(body).unwrap()));
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn webhook(url: &str, template: Option<&str>, attempts: u32) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            template: template.map(|t| t.to_string()),
+            content_type: default_content_type(),
+            attempts,
+            retry_delay: "0s".parse().unwrap(),
+            timeout: default_timeout(),
+            when: When::Always,
+        }
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            volumes: vec!["home".into()],
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn posts_template() {
+        let (url, server) = serve(vec![200]);
+        let hook = webhook(
+            &url,
+            Some("{\"text\": \"{{subject}}\", \"ok\": \"{{outcome}}\"}"),
+            1,
+        );
+        hook.send(&summary(), "host").unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (head, body) = &requests[0];
+        assert!(
+            head.starts_with("POST /hook?token=secret HTTP/1.1\r\n"),
+            "{}",
+            head
+        );
+        assert!(
+            head.contains("Content-Type: application/json\r\n"),
+            "{}",
+            head
+        );
+        assert_eq!(body, "{\"text\": \"rdump host: home ok\", \"ok\": \"ok\"}");
+    }
+
+    #[test]
+    fn posts_summary() {
+        let (url, server) = serve(vec![200]);
+        webhook(&url, None, 1).send(&summary(), "host").unwrap();
+
+        let requests = server.join().unwrap();
+        let posted: Summary = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(posted.volumes, vec!["home".to_string()]);
+    }
+
+    #[test]
+    fn body_is_exact() {
+        let template = "line \"one\"\r\nline\ttwo \\ @three\n";
+        let (url, server) = serve(vec![200]);
+        webhook(&url, Some(template), 1)
+            .send(&summary(), "host")
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].1, template);
+    }
+
+    #[test]
+    fn unreadable_history() {
+        // A directory can be opened, but not read as the history.
+        let dir = env::temp_dir().join(format!("rdump-test-{}-notify", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (url, server) = serve(vec![200]);
+        let mut hook = webhook(&url, None, 1);
+        hook.when = When::Change;
+        let notifier = Notifier::new(None, Some(hook), History::new(&dir));
+
+        // Without a previous run, a failure is a change.
+        let failed = Summary {
+            error: Some("failed".into()),
+            ..summary()
+        };
+        let result = notifier.send(&failed);
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retries() {
+        let (url, server) = serve(vec![500, 200]);
+        webhook(&url, None, 2).send(&summary(), "host").unwrap();
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        let (url, server) = serve(vec![500, 503]);
+        assert!(webhook(&url, None, 2).send(&summary(), "host").is_err());
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn timeout_rounds_up() {
+        let mut hook = webhook("http://localhost/", None, 1);
+        for &(millis, secs) in &[(0, 1), (1, 1), (999, 1), (1000, 1), (1001, 2), (30000, 30)] {
+            hook.timeout = Period::from_duration(Duration::milliseconds(millis));
+            assert_eq!(hook.max_time(), secs, "{}ms", millis);
+        }
+    }
+
+    /// A run of the given volumes, starting `hours` after the first, that
+    /// succeeded or failed.
+    fn run(hours: i64, volumes: &[&str], ok: bool) -> Record {
+        let start = timestamp::parse("20210601T000000").unwrap() + Duration::hours(hours);
+        Record {
+            id: format!("run-{}", hours),
+            host: "host".into(),
+            snapshots: vec![],
+            summary: Summary {
+                start,
+                end: start + Duration::minutes(30),
+                volumes: volumes.iter().map(|v| v.to_string()).collect(),
+                error: if ok { None } else { Some("failed".into()) },
+                ..Summary::default()
+            },
+        }
+    }
+
+    #[test]
+    fn finds_previous() {
+        let history = vec![
+            run(0, &["home"], true),
+            run(1, &["root"], false),
+            run(2, &["home", "root"], false),
+            run(3, &["root"], true),
+        ];
+        let id = |rec: Option<&Record>| rec.map(|r| r.id.clone());
+        // The run itself is in the history, and is skipped.
+        assert_eq!(
+            id(previous(&history, &history[3].summary)),
+            Some("run-2".into())
+        );
+        assert_eq!(
+            id(previous(&history, &run(4, &["home"], true).summary)),
+            Some("run-2".into())
+        );
+        assert_eq!(
+            id(previous(&history, &run(4, &["boot"], true).summary)),
+            None
+        );
+        assert_eq!(id(previous(&history, &history[0].summary)), None);
+    }
+
+    #[test]
+    fn on_change() {
+        let ok = run(1, &["home"], true);
+        let failed = run(1, &["home"], false);
+        let wanted = |prev: Option<&Record>, now: &Record| When::Change.wanted(&now.summary, prev);
+
+        // The previous run succeeded.
+        let prev = run(0, &["home"], true);
+        assert!(!wanted(Some(&prev), &ok));
+        assert!(wanted(Some(&prev), &failed));
+
+        // The previous run failed.
+        let prev = run(0, &["home"], false);
+        assert!(wanted(Some(&prev), &ok));
+        assert!(!wanted(Some(&prev), &failed));
+
+        // With no previous run, only a failure is news.
+        assert!(!wanted(None, &ok));
+        assert!(wanted(None, &failed));
+    }
+
+    #[test]
+    fn on_change_from_history() {
+        // A failure followed by a success of the same volume is a change,
+        // even with an unrelated failure in between.
+        let history = vec![
+            run(0, &["home"], false),
+            run(1, &["root"], false),
+            run(2, &["home"], true),
+        ];
+        let now = &history[2].summary;
+        assert!(When::Change.wanted(now, previous(&history, now)));
+        assert!(!When::Failure.wanted(now, previous(&history, now)));
+        assert!(When::Always.wanted(now, previous(&history, now)));
+
+        let now = &run(3, &["home"], true).summary;
+        assert!(!When::Change.wanted(now, previous(&history, now)));
+    }
+}
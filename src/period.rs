@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Time periods in the config file.
+//!
+//! Periods are written as a number followed by a unit, such as `30s`,
+//! `15m`, `4h`, `2d`, or `1w`.  A few names are also accepted for common
+//! schedules: `hourly`, `daily`, `weekly`, and `monthly` (which is treated
+//! as 30 days).
+
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Period(Duration);
+
+impl Period {
+    pub fn from_duration(duration: Duration) -> Period {
+        Period(duration)
+    }
+
+    /// Return this period as a chrono Duration.
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for Period {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Period> {
+        let text = text.trim();
+        match text {
+            "hourly" => return Ok(Period(Duration::hours(1))),
+            "daily" => return Ok(Period(Duration::days(1))),
+            "weekly" => return Ok(Period(Duration::weeks(1))),
+            "monthly" => return Ok(Period(Duration::days(30))),
+            _ => (),
+        }
+
+        let split = text
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| anyhow!("Period {:?} has no unit", text))?;
+        let (num, unit) = text.split_at(split);
+        let num: i64 = num
+            .parse()
+            .map_err(|_| anyhow!("Invalid period {:?}", text))?;
+        let size = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 604800,
+            _ => return Err(anyhow!("Unknown unit in period {:?}", text)),
+        };
+        // Chrono panics on durations of more than i64::MAX milliseconds.
+        let secs = num
+            .checked_mul(size)
+            .filter(|&secs| secs <= i64::MAX / 1000)
+            .ok_or_else(|| anyhow!("Period {:?} is too long", text))?;
+        Ok(Period(Duration::seconds(secs)))
+    }
+}
+
+impl TryFrom<String> for Period {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Period> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for Period {
+    // Show the period using the largest unit that represents it exactly.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.num_seconds();
+        for &(unit, size) in &[("w", 604800), ("d", 86400), ("h", 3600), ("m", 60)] {
+            if secs != 0 && secs % size == 0 {
+                return write!(f, "{}{}", secs / size, unit);
+            }
+        }
+        write!(f, "{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(text: &str) -> i64 {
+        text.parse::<Period>().unwrap().duration().num_seconds()
+    }
+
+    fn error(text: &str) -> String {
+        text.parse::<Period>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(secs("hourly"), 3600);
+        assert_eq!(secs("daily"), 86400);
+        assert_eq!(secs("weekly"), 7 * 86400);
+        assert_eq!(secs(" monthly "), 30 * 86400);
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(secs("30s"), 30);
+        assert_eq!(secs("15m"), 15 * 60);
+        assert_eq!(secs("4h"), 4 * 3600);
+        assert_eq!(secs("2d"), 2 * 86400);
+        assert_eq!(secs("1w"), 7 * 86400);
+        assert_eq!(secs("0s"), 0);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(error("30"), "Period \"30\" has no unit");
+        assert_eq!(error("3y"), "Unknown unit in period \"3y\"");
+        assert_eq!(error("3 d"), "Unknown unit in period \"3 d\"");
+        assert_eq!(error("d"), "Invalid period \"d\"");
+        assert_eq!(error("-1d"), "Invalid period \"-1d\"");
+        assert_eq!(
+            error("99999999999999w"),
+            "Period \"99999999999999w\" is too long"
+        );
+        assert_eq!(
+            error("99999999999999999999s"),
+            "Invalid period \"99999999999999999999s\""
+        );
+        // The longest period that chrono can represent, and just past it.
+        let max = i64::MAX / 1000;
+        assert_eq!(secs(&format!("{}s", max)), max);
+        assert!(format!("{}s", max + 1).parse::<Period>().is_err());
+    }
+
+    #[test]
+    fn display() {
+        for &text in &["30s", "90s", "15m", "4h", "2d", "1w", "0s"] {
+            assert_eq!(text.parse::<Period>().unwrap().to_string(), text);
+        }
+        assert_eq!("monthly".parse::<Period>().unwrap().to_string(), "30d");
+        assert_eq!("120m".parse::<Period>().unwrap().to_string(), "2h");
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Restoring from borg.
+//!
+//! Archives are named after the volume and the time of the backup, and
+//! hold the files under the path that was backed up (for LVM volumes,
+//! the snapshot mount point).  Restore finds the right archive, and
+//! extracts it into a target directory with this prefix removed, then
+//! verifies the result against the surefile that was captured in the
+//! archive.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rsure::Version;
+use std::{env, fs, path::Path, process};
+
+use crate::{
+    borg::{Archive, Borg},
+    config::Volume,
+    surefile::{self, Changes},
+};
+
+/// Restore `volume` from the most recent archive made at or before `at`
+/// (or the most recent archive, if not given).  If `path` is given, only
+/// that path, relative to the root of the volume, is extracted.
+pub fn restore(
+    borg: &Borg,
+    volume: &Volume,
+    at: Option<DateTime<Utc>>,
+    path: Option<&str>,
+    target: &Path,
+    pretend: bool,
+) -> Result<()> {
+    let archives = borg.archives(volume.name)?;
+    let archive = select(&archives, volume.name, at)?;
+    let (paths, strip) = extract_paths(volume.source, path);
+    let paths: Vec<_> = paths.iter().map(|p| p.as_str()).collect();
+
+    if pretend {
+        println!(
+            "would: extract {:?} from {} into {:?}, stripping {} components",
+            paths, archive.name, target, strip
+        );
+        return Ok(());
+    }
+
+    println!("Restoring {} from {}", volume.name, archive.name);
+    fs::create_dir_all(target)?;
+    borg.extract(&archive.name, &paths, strip, target)?;
+
+    // The surefile only describes the whole volume, so a partial restore
+    // can't be checked against it.
+    if path.is_some() {
+        info!("Skipping rsure check of partial restore");
+        return Ok(());
+    }
+
+    let surefile = target.join("2sure.dat.gz");
+    if !surefile.is_file() {
+        warn!("No surefile in archive, unable to check restore");
+        return Ok(());
+    }
+
+    info!("Rsure check of {:?}", target);
+    let changes = check(&surefile, target)?;
+    if changes.count() > 0 {
+        for path in &changes.added {
+            println!("  added: {}", path);
+        }
+        for path in &changes.removed {
+            println!("  missing: {}", path);
+        }
+        for path in &changes.modified {
+            println!("  changed: {}", path);
+        }
+        return Err(anyhow!(
+            "Restore of {} differs from its surefile in {} files",
+            volume.name,
+            changes.count()
+        ));
+    }
+    println!("Restore matches its surefile");
+
+    Ok(())
+}
+
+/// Scan the restored tree, and compare it with the latest version in the
+/// surefile from the archive.  The scan is written to a surefile of its
+/// own, outside of the tree, so that it isn't part of what is scanned.
+fn check(surefile: &Path, target: &Path) -> Result<Changes> {
+    let archived = rsure::parse_store(&surefile.to_string_lossy())?;
+    let expected = surefile::load(&*archived, Version::Latest)?;
+
+    let dir = env::temp_dir().join(format!("rdump-restore-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let scan = || -> Result<surefile::Files> {
+        let store = rsure::parse_store(&dir.join("2sure.dat.gz").to_string_lossy())?;
+        let mut tags = rsure::StoreTags::new();
+        tags.insert("name".into(), "restore".into());
+        rsure::update(target, &*store, false, &tags)?;
+        surefile::load(&*store, Version::Latest)
+    };
+    let result = scan();
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        warn!("Unable to remove {:?}: {}", dir, err);
+    }
+    Ok(compare(expected, result?))
+}
+
+/// Find the most recent of the `archives` (oldest first) made at or before
+/// `at`, or the most recent archive if not given.
+fn select<'a>(
+    archives: &'a [Archive],
+    name: &str,
+    at: Option<DateTime<Utc>>,
+) -> Result<&'a Archive> {
+    archives
+        .iter()
+        .rev()
+        .find(|a| match at {
+            Some(at) => a.time <= at,
+            None => true,
+        })
+        .ok_or_else(|| match at {
+            Some(at) => anyhow!("No archive of {} at or before {}", name, at),
+            None => anyhow!("No archives of {}", name),
+        })
+}
+
+/// The paths to extract from an archive of the volume backed up from
+/// `source`, and the number of leading components to strip from them so
+/// that they are relative to the root of the volume.  With `path`, only
+/// that path within the volume is extracted.
+fn extract_paths(source: &str, path: Option<&str>) -> (Vec<String>, usize) {
+    // Borg stores the paths without the leading slash.
+    let prefix = source.trim_matches('/');
+    let strip = if prefix.is_empty() {
+        0
+    } else {
+        prefix.split('/').count()
+    };
+
+    let mut paths = vec![];
+    match path.map(|p| p.trim_matches('/')) {
+        Some(sub) if prefix.is_empty() => paths.push(sub.to_string()),
+        Some(sub) => paths.push(format!("{}/{}", prefix, sub)),
+        None if prefix.is_empty() => (),
+        None => paths.push(prefix.to_string()),
+    }
+    (paths, strip)
+}
+
+/// Compare the files recorded in the archived surefile with those found by
+/// scanning the restore.
+fn compare(mut expected: surefile::Files, mut found: surefile::Files) -> Changes {
+    // The surefiles themselves were rewritten after the scan they record.
+    for files in [&mut expected, &mut found].iter_mut() {
+        files.retain(|path, _| !path.starts_with("2sure.") || path.contains('/'));
+    }
+    Changes::between(&expected, &found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp;
+    use chrono::TimeZone;
+
+    fn time(text: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&text.parse().unwrap())
+    }
+
+    /// Archives of `home` at the given times, named as a backup would name
+    /// them, with a retried backup at the last.
+    fn archives() -> Vec<Archive> {
+        [
+            "2021-06-01T12:00:00",
+            "2021-06-02T12:00:00",
+            "2021-06-03T12:00:00",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let mut name = format!("home-{}", time(t).format(timestamp::FORMAT));
+            if i == 2 {
+                name.push_str(".2");
+            }
+            let time = timestamp::parse_named("home", &name).unwrap();
+            Archive { name, time }
+        })
+        .collect()
+    }
+
+    #[test]
+    fn select_archive() {
+        let archives = archives();
+        let name = |at: Option<&str>| {
+            select(&archives, "home", at.map(time))
+                .map(|a| a.name.clone())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(name(None).unwrap(), "home-20210603T120000.2");
+        assert_eq!(
+            name(Some("2021-06-03T12:00:00")).unwrap(),
+            "home-20210603T120000.2"
+        );
+        assert_eq!(
+            name(Some("2021-06-02T12:00:00")).unwrap(),
+            "home-20210602T120000"
+        );
+        assert_eq!(
+            name(Some("2021-06-03T11:59:59")).unwrap(),
+            "home-20210602T120000"
+        );
+        assert_eq!(
+            name(Some("2021-06-01T00:00:00")).unwrap_err(),
+            "No archive of home at or before 2021-06-01 00:00:00 UTC"
+        );
+        assert_eq!(
+            select(&[], "home", None).unwrap_err().to_string(),
+            "No archives of home"
+        );
+    }
+
+    #[test]
+    fn paths_of_volume() {
+        let paths = |source, path| {
+            let (paths, strip) = extract_paths(source, path);
+            (paths.join(" "), strip)
+        };
+        // An LVM volume, backed up from its snapshot mount.
+        assert_eq!(paths("/mnt/snap/home", None), ("mnt/snap/home".into(), 3));
+        assert_eq!(
+            paths("/mnt/snap/home/", Some("/david/notes/")),
+            ("mnt/snap/home/david/notes".into(), 3)
+        );
+        // A volume backed up from the root.
+        assert_eq!(paths("/", None), ("".into(), 0));
+        assert_eq!(paths("/", Some("etc")), ("etc".into(), 0));
+    }
+
+    fn files(entries: &[(&str, &str)]) -> surefile::Files {
+        entries
+            .iter()
+            .map(|(path, sha)| {
+                let mut atts = rsure::AttMap::new();
+                atts.insert("sha1".into(), sha.to_string());
+                (path.to_string(), atts)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compare_with_surefile() {
+        let expected = files(&[
+            ("2sure.dat.gz", "a"),
+            ("2sure.weave.gz", "b"),
+            ("notes", "c"),
+            ("src/main.rs", "d"),
+            ("src/2sure.dat.gz", "e"),
+        ]);
+
+        // The surefiles at the root are rewritten by every scan, and don't
+        // count as changes.
+        let found = files(&[
+            ("2sure.dat.gz", "x"),
+            ("notes", "c"),
+            ("src/main.rs", "d"),
+            ("src/2sure.dat.gz", "e"),
+        ]);
+        assert_eq!(compare(expected.clone(), found).count(), 0);
+
+        let found = files(&[("notes", "changed"), ("new", "f"), ("src/main.rs", "d")]);
+        let changes = compare(expected, found);
+        assert_eq!(changes.added, vec!["new"]);
+        assert_eq!(changes.removed, vec!["src/2sure.dat.gz"]);
+        assert_eq!(changes.modified, vec!["notes"]);
+    }
+}
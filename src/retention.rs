@@ -0,0 +1,366 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Snapshot retention.
+//!
+//! A retention policy decides which of the snapshots of a filesystem to
+//! keep.  It is made of rules, and a snapshot is kept if any of the rules
+//! keeps it:
+//!
+//! - `latest`: the given number of most recent snapshots.
+//! - `within`: every snapshot newer than the given period.
+//! - `hourly`, `daily`, `weekly`, `monthly`: the newest snapshot of each
+//!   of the given number of most recent hours, days, (ISO) weeks, or
+//!   months that have snapshots, in local time.
+//! - `hanoi`: the newest snapshot with each number of bits set in its
+//!   number, from those older than the `latest` ones.  This keeps fewer
+//!   snapshots the further back it goes, in a Tower of Hanoi pattern.
+//!   Snapshots are numbered by their sequence number, if they have one,
+//!   otherwise by the day they were made.
+//!
+//! The newest snapshot is always kept, as it is the base for the next
+//! incremental send.  Only snapshots whose names give the time they were
+//! made are managed, any others are left alone.
+//!
+//! A policy must have a rule that keeps more than the newest snapshot,
+//! however old they are: `hanoi`, or a count of at least two.  Otherwise,
+//! once a `within` period passes without a new snapshot, the policy would
+//! prune all but the newest, as a count of one only keeps the newest.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::Deserialize;
+use std::{collections::BTreeSet, convert::TryFrom};
+
+use crate::period::Period;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawRetention")]
+pub struct Retention {
+    pub latest: usize,
+    pub within: Option<Period>,
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub hanoi: bool,
+}
+
+/// A retention policy as given in the config, before it is checked.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRetention {
+    #[serde(default)]
+    latest: usize,
+    within: Option<Period>,
+    #[serde(default)]
+    hourly: usize,
+    #[serde(default)]
+    daily: usize,
+    #[serde(default)]
+    weekly: usize,
+    #[serde(default)]
+    monthly: usize,
+    #[serde(default)]
+    hanoi: bool,
+}
+
+/// A snapshot to decide about.
+pub struct Snapshot<'a> {
+    pub name: &'a str,
+    pub time: DateTime<Utc>,
+    /// The sequence number of the snapshot, if its name has one.
+    pub number: Option<u64>,
+}
+
+impl Retention {
+    /// Decide which of the snapshots, given oldest first, to keep.
+    /// Returns the reason each is kept, or None for those to prune.
+    pub fn decide(&self, snaps: &[Snapshot], now: DateTime<Utc>) -> Vec<Option<&'static str>> {
+        let mut keep = vec![None; snaps.len()];
+        let count = snaps.len();
+        if count == 0 {
+            return keep;
+        }
+        keep[count - 1] = Some("newest");
+
+        let latest = count.saturating_sub(self.latest);
+        for reason in &mut keep[latest..] {
+            reason.get_or_insert("latest");
+        }
+
+        if let Some(within) = self.within {
+            let cutoff = now - within.duration();
+            for (snap, reason) in snaps.iter().zip(keep.iter_mut()) {
+                if snap.time >= cutoff {
+                    reason.get_or_insert("within");
+                }
+            }
+        }
+
+        let buckets = [
+            (self.hourly, "hourly", "%Y%m%d%H"),
+            (self.daily, "daily", "%Y%m%d"),
+            (self.weekly, "weekly", "%G%V"),
+            (self.monthly, "monthly", "%Y%m"),
+        ];
+        for &(limit, name, format) in &buckets {
+            let mut seen = BTreeSet::new();
+            for (snap, reason) in snaps.iter().zip(keep.iter_mut()).rev() {
+                if seen.len() == limit {
+                    break;
+                }
+                let bucket = snap.time.with_timezone(&Local).format(format).to_string();
+                if seen.insert(bucket) {
+                    reason.get_or_insert(name);
+                }
+            }
+        }
+
+        if self.hanoi {
+            let mut pops = BTreeSet::new();
+            for (snap, reason) in snaps[..latest].iter().zip(keep[..latest].iter_mut()).rev() {
+                let number = snap
+                    .number
+                    .unwrap_or_else(|| snap.time.timestamp().div_euclid(86400) as u64);
+                if pops.insert(number.count_ones()) {
+                    reason.get_or_insert("hanoi");
+                }
+            }
+        }
+
+        keep
+    }
+}
+
+impl TryFrom<RawRetention> for Retention {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawRetention) -> Result<Retention> {
+        let counts = [raw.latest, raw.hourly, raw.daily, raw.weekly, raw.monthly];
+        if !raw.hanoi && counts.iter().all(|&count| count < 2) {
+            return Err(anyhow!(
+                "retention must keep more than the newest snapshot, with hanoi, or at least \
+                 2 of latest, hourly, daily, weekly or monthly"
+            ));
+        }
+        Ok(Retention {
+            latest: raw.latest,
+            within: raw.within,
+            hourly: raw.hourly,
+            daily: raw.daily,
+            weekly: raw.weekly,
+            monthly: raw.monthly,
+            hanoi: raw.hanoi,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn policy(text: &str) -> Retention {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    fn at(text: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&text.parse().unwrap())
+    }
+
+    /// Decide about the snapshots, returning those kept, and why.
+    fn kept<'a>(
+        policy: &Retention,
+        snaps: &[Snapshot<'a>],
+        now: DateTime<Utc>,
+    ) -> Vec<(&'a str, &'static str)> {
+        let decisions = policy.decide(snaps, now);
+        snaps
+            .iter()
+            .zip(decisions)
+            .filter_map(|(snap, reason)| reason.map(|r| (snap.name, r)))
+            .collect()
+    }
+
+    /// Snapshots at the given times, named by them.
+    fn snaps<'a>(times: &'a [String]) -> Vec<Snapshot<'a>> {
+        times
+            .iter()
+            .map(|time| Snapshot {
+                name: time,
+                time: at(time),
+                number: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn needs_a_rule() {
+        let invalid = [
+            "{}",
+            "within: 30d",
+            "latest: 0",
+            "latest: 1",
+            "latest: 1\nwithin: 30d",
+            "daily: 1\nweekly: 1",
+            "hanoi: false\nwithin: 1d",
+        ];
+        for text in &invalid {
+            assert!(
+                serde_yaml::from_str::<Retention>(text).is_err(),
+                "{:?} accepted",
+                text
+            );
+        }
+        for text in &[
+            "latest: 2",
+            "daily: 7",
+            "latest: 1\nmonthly: 2",
+            "within: 30d\nhanoi: true",
+        ] {
+            serde_yaml::from_str::<Retention>(text).unwrap();
+        }
+        assert!(serde_yaml::from_str::<Retention>("dialy: 7").is_err());
+    }
+
+    #[test]
+    fn nothing() {
+        assert!(policy("latest: 3").decide(&[], Utc::now()).is_empty());
+    }
+
+    #[test]
+    fn latest() {
+        let times: Vec<_> = (1..=5)
+            .map(|day| format!("2021-06-0{}T12:00:00", day))
+            .collect();
+        let snaps = snaps(&times);
+        assert_eq!(
+            kept(&policy("latest: 2"), &snaps, at("2021-06-06T00:00:00")),
+            vec![
+                ("2021-06-04T12:00:00", "latest"),
+                ("2021-06-05T12:00:00", "newest")
+            ]
+        );
+    }
+
+    #[test]
+    fn daily() {
+        // Three a day, close enough together to share a local day in any
+        // time zone.
+        let mut times = vec![];
+        for day in 1..=4 {
+            for minute in &["00", "05", "10"] {
+                times.push(format!("2021-06-0{}T11:{}:00", day, minute));
+            }
+        }
+        let snaps = snaps(&times);
+        assert_eq!(
+            kept(&policy("daily: 3"), &snaps, at("2021-06-05T00:00:00")),
+            vec![
+                ("2021-06-02T11:10:00", "daily"),
+                ("2021-06-03T11:10:00", "daily"),
+                ("2021-06-04T11:10:00", "newest")
+            ]
+        );
+    }
+
+    #[test]
+    fn buckets() {
+        // One on the 1st and 15th of each month of the first half of 2021.
+        let mut times = vec![];
+        for month in 1..=6 {
+            for &day in &[1, 15] {
+                times.push(format!("2021-{:02}-{:02}T12:00:00", month, day));
+            }
+        }
+        let snaps = snaps(&times);
+        let now = at("2021-07-01T00:00:00");
+        assert_eq!(
+            kept(&policy("monthly: 3"), &snaps, now),
+            vec![
+                ("2021-04-15T12:00:00", "monthly"),
+                ("2021-05-15T12:00:00", "monthly"),
+                ("2021-06-15T12:00:00", "newest")
+            ]
+        );
+        assert_eq!(
+            kept(&policy("weekly: 2\nhourly: 2"), &snaps, now),
+            vec![
+                ("2021-06-01T12:00:00", "hourly"),
+                ("2021-06-15T12:00:00", "newest")
+            ]
+        );
+        assert_eq!(
+            kept(&policy("monthly: 4\nweekly: 3"), &snaps, now),
+            vec![
+                ("2021-03-15T12:00:00", "monthly"),
+                ("2021-04-15T12:00:00", "monthly"),
+                ("2021-05-15T12:00:00", "weekly"),
+                ("2021-06-01T12:00:00", "weekly"),
+                ("2021-06-15T12:00:00", "newest")
+            ]
+        );
+    }
+
+    #[test]
+    fn within() {
+        let now = at("2021-06-10T00:00:00");
+        let times: Vec<_> = [72, 48, 24, 1]
+            .iter()
+            .map(|&hours| {
+                (now - Duration::hours(hours))
+                    .naive_utc()
+                    .format("%Y-%m-%dT%H:%M:%S")
+                    .to_string()
+            })
+            .collect();
+        let snaps = snaps(&times);
+        assert_eq!(
+            kept(&policy("latest: 2\nwithin: 2d"), &snaps, now),
+            vec![
+                ("2021-06-08T00:00:00", "within"),
+                ("2021-06-09T00:00:00", "latest"),
+                ("2021-06-09T23:00:00", "newest")
+            ]
+        );
+        // Once the period passes with no new snapshots, only those kept
+        // by other rules are.
+        let later = now + Duration::days(7);
+        assert_eq!(
+            kept(&policy("latest: 2\nwithin: 2d"), &snaps, later),
+            vec![
+                ("2021-06-09T00:00:00", "latest"),
+                ("2021-06-09T23:00:00", "newest")
+            ]
+        );
+    }
+
+    #[test]
+    fn hanoi() {
+        let names: Vec<_> = (1..=10).map(|n| format!("s{}", n)).collect();
+        let snaps: Vec<_> = names
+            .iter()
+            .zip(1..)
+            .map(|(name, number)| Snapshot {
+                name,
+                time: at("2021-06-01T00:00:00") + Duration::hours(number as i64),
+                number: Some(number),
+            })
+            .collect();
+        // Of those older than the latest two, 8, 7 and 6 are the newest
+        // with one, three and two bits set.
+        assert_eq!(
+            kept(
+                &policy("latest: 2\nhanoi: true"),
+                &snaps,
+                at("2021-06-02T00:00:00")
+            ),
+            vec![
+                ("s6", "hanoi"),
+                ("s7", "hanoi"),
+                ("s8", "hanoi"),
+                ("s9", "latest"),
+                ("s10", "newest")
+            ]
+        );
+    }
+}
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Calendar schedules in the config file.
+//!
+//! Schedules are written as a subset of the systemd `OnCalendar` syntax,
+//! so that the same schedule can be given to a systemd timer, or run by
+//! `rdump daemon`.  A schedule is an optional list of weekdays, an
+//! optional `*-*-*` date, and a time of `hour:minute[:second]`, in local
+//! time.  The weekdays may be names, or ranges such as `Mon..Fri`.  Each
+//! part of the time may be `*`, a number, a range such as `9..17`, or a
+//! repetition such as `0/4` (every fourth hour, starting at 0) or `*/4`
+//! (the same), or a comma-separated list of these.  For example:
+//!
+//! ```text
+//! *-*-* 02:30
+//! Mon..Fri 0/4:00
+//! Sat,Sun 12:00:00
+//! ```
+//!
+//! The names `hourly`, `daily` and `weekly` are also accepted, with the
+//! same meaning as systemd gives them.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Weekday};
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    /// The schedule as written, which is also valid for systemd.
+    text: String,
+    /// The days of the week, or None for every day.
+    days: Option<Vec<Weekday>>,
+    hours: Vec<u32>,
+    minutes: Vec<u32>,
+    seconds: Vec<u32>,
+}
+
+impl Schedule {
+    /// Return the first time of this schedule after `time`.  Times that
+    /// daylight saving skips over are left out.
+    pub fn next_after<Tz: TimeZone>(&self, time: DateTime<Tz>) -> DateTime<Tz> {
+        // Every schedule has a time within each week, so this will always
+        // find one, unless daylight saving skips over all of them.
+        for day in 0..=8 {
+            let date = time.naive_local().date() + Duration::days(day);
+            if let Some(ref days) = self.days {
+                if !days.contains(&date.weekday()) {
+                    continue;
+                }
+            }
+            for &hour in &self.hours {
+                for &minute in &self.minutes {
+                    for &second in &self.seconds {
+                        let naive = match date.and_hms_opt(hour, minute, second) {
+                            Some(naive) => naive,
+                            None => continue,
+                        };
+                        match time.timezone().from_local_datetime(&naive).earliest() {
+                            Some(next) if next > time => return next,
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+        time + Duration::weeks(1)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Schedule> {
+        let text = text.trim();
+        let full = match text {
+            "hourly" => "*-*-* *:00:00",
+            "daily" => "*-*-* 00:00:00",
+            "weekly" => "Mon *-*-* 00:00:00",
+            text => text,
+        };
+
+        let mut words: Vec<_> = full.split_whitespace().collect();
+        let time = words.pop().ok_or_else(|| anyhow!("Empty schedule"))?;
+        if words.last() == Some(&"*-*-*") {
+            words.pop();
+        }
+        let days = match words[..] {
+            [] => None,
+            [days] => Some(parse_days(days).map_err(|e| anyhow!("Schedule {:?}: {}", text, e))?),
+            _ => return Err(anyhow!("Unsupported schedule {:?}", text)),
+        };
+
+        let parts: Vec<_> = time.split(':').collect();
+        let (hours, minutes, seconds) = match parts[..] {
+            [h, m] => (h, m, "0"),
+            [h, m, s] => (h, m, s),
+            _ => return Err(anyhow!("Schedule {:?} needs a time of hour:minute", text)),
+        };
+        let field = |part, limit| {
+            parse_field(part, limit).map_err(|e| anyhow!("Schedule {:?}: {}", text, e))
+        };
+        Ok(Schedule {
+            text: text.into(),
+            days,
+            hours: field(hours, 24)?,
+            minutes: field(minutes, 60)?,
+            seconds: field(seconds, 60)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Schedule> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Parse a list of weekdays, such as `Mon..Fri,Sun`.
+fn parse_days(text: &str) -> Result<Vec<Weekday>> {
+    let day = |name: &str| {
+        name.parse::<Weekday>()
+            .map_err(|_| anyhow!("Unknown weekday {:?}", name))
+    };
+    let mut days = vec![];
+    for item in text.split(',') {
+        match item.split_once("..") {
+            Some((first, last)) => {
+                let (mut d, last) = (day(first)?, day(last)?);
+                days.push(d);
+                while d != last {
+                    d = d.succ();
+                    days.push(d);
+                }
+            }
+            None => days.push(day(item)?),
+        }
+    }
+    Ok(days)
+}
+
+/// Parse one part of a time, whose values must be less than `limit`,
+/// returning the values in order.
+fn parse_field(text: &str, limit: u32) -> Result<Vec<u32>> {
+    let num = |n: &str| -> Result<u32> {
+        let value = n.parse().map_err(|_| anyhow!("Invalid number {:?}", n))?;
+        if value >= limit {
+            return Err(anyhow!("{} is out of range", value));
+        }
+        Ok(value)
+    };
+    let mut values = vec![];
+    for item in text.split(',') {
+        if item.is_empty() {
+            return Err(anyhow!("Empty field in {:?}", text));
+        } else if item == "*" {
+            values.extend(0..limit);
+        } else if let Some((start, step)) = item.split_once('/') {
+            let step: u32 = step
+                .parse()
+                .map_err(|_| anyhow!("Invalid repetition {:?}", item))?;
+            if step == 0 {
+                return Err(anyhow!("Invalid repetition {:?}", item));
+            }
+            let start = if start == "*" { 0 } else { num(start)? };
+            values.extend((start..limit).step_by(step as usize));
+        } else if let Some((first, last)) = item.split_once("..") {
+            let (first, last) = (num(first)?, num(last)?);
+            if first > last {
+                return Err(anyhow!("Reversed range {:?}", item));
+            }
+            values.extend(first..=last);
+        } else {
+            values.push(num(item)?);
+        }
+    }
+    values.sort_unstable();
+    values.dedup();
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{
+        FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc, Weekday::*,
+    };
+
+    fn at(text: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&text.parse().unwrap())
+    }
+
+    fn next(schedule: &str, time: &str) -> DateTime<Utc> {
+        let schedule: Schedule = schedule.parse().unwrap();
+        schedule.next_after(at(time))
+    }
+
+    #[test]
+    fn ranges() {
+        let schedule: Schedule = "Mon..Wed,Sat 9..11:15,45".parse().unwrap();
+        assert_eq!(schedule.days, Some(vec![Mon, Tue, Wed, Sat]));
+        assert_eq!(schedule.hours, vec![9, 10, 11]);
+        assert_eq!(schedule.minutes, vec![15, 45]);
+        assert_eq!(schedule.seconds, vec![0]);
+    }
+
+    #[test]
+    fn repetition() {
+        let schedule: Schedule = "0/4:*/20:30/15".parse().unwrap();
+        assert_eq!(schedule.hours, vec![0, 4, 8, 12, 16, 20]);
+        assert_eq!(schedule.minutes, vec![0, 20, 40]);
+        assert_eq!(schedule.seconds, vec![30, 45]);
+    }
+
+    #[test]
+    fn wrapping_days() {
+        let schedule: Schedule = "Fri..Mon 12:00".parse().unwrap();
+        assert_eq!(schedule.days, Some(vec![Fri, Sat, Sun, Mon]));
+        // 2021-06-01 is a Tuesday.
+        assert_eq!(
+            next("Fri..Mon 12:00", "2021-06-01T13:00:00"),
+            at("2021-06-04T12:00:00")
+        );
+        assert_eq!(
+            next("Fri..Mon 12:00", "2021-06-06T12:00:00"),
+            at("2021-06-07T12:00:00")
+        );
+    }
+
+    #[test]
+    fn names() {
+        let time = "2021-06-01T13:20:00";
+        assert_eq!(next("hourly", time), at("2021-06-01T14:00:00"));
+        assert_eq!(next("daily", time), at("2021-06-02T00:00:00"));
+        assert_eq!(next("weekly", time), at("2021-06-07T00:00:00"));
+    }
+
+    #[test]
+    fn strictly_after() {
+        let time = "2021-06-01T02:30:00";
+        assert_eq!(next("*-*-* 02:30", time), at("2021-06-02T02:30:00"));
+        assert_eq!(next("*:*:*", time), at("2021-06-01T02:30:01"));
+    }
+
+    #[test]
+    fn invalid() {
+        for text in &[
+            "",
+            "daily 02:00",
+            "12",
+            "24:00",
+            "12:60",
+            "Mon..Fry 12:00",
+            "0/0:00",
+            "x/2:00",
+            "Mon 2021-06-01 12:00",
+            "17..9:00",
+            "12:30..15",
+            "12:",
+            ":00",
+            "1,,2:00",
+            "12:00:",
+        ] {
+            assert!(text.parse::<Schedule>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    /// A time zone that skips from 02:00 to 03:00 on 2021-03-28.
+    #[derive(Clone, Copy, Debug)]
+    struct Spring;
+
+    impl Spring {
+        fn change() -> NaiveDateTime {
+            NaiveDate::from_ymd_opt(2021, 3, 28)
+                .unwrap()
+                .and_hms_opt(2, 0, 0)
+                .unwrap()
+        }
+
+        fn offset(summer: bool) -> FixedOffset {
+            FixedOffset::east_opt(if summer { 3600 } else { 0 }).unwrap()
+        }
+    }
+
+    impl TimeZone for Spring {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Spring {
+            Spring
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let change = Spring::change();
+            if *local < change {
+                LocalResult::Single(Spring::offset(false))
+            } else if *local < change + Duration::hours(1) {
+                LocalResult::None
+            } else {
+                LocalResult::Single(Spring::offset(true))
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            Spring::offset(*utc >= Spring::change())
+        }
+    }
+
+    #[test]
+    fn daylight_saving_gap() {
+        let local = |text: &str| Spring.from_local_datetime(&text.parse().unwrap()).unwrap();
+        let next = |schedule: &str, time: &str| {
+            let schedule: Schedule = schedule.parse().unwrap();
+            schedule.next_after(local(time))
+        };
+        assert_eq!(
+            local("2021-03-28T03:00:00").offset().fix(),
+            Spring::offset(true)
+        );
+        assert_eq!(
+            next("*-*-* 02:30", "2021-03-28T00:00:00"),
+            local("2021-03-29T02:30:00")
+        );
+        assert_eq!(
+            next("hourly", "2021-03-28T01:30:00"),
+            local("2021-03-28T03:00:00")
+        );
+    }
+}
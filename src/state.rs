@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Persistent state.
+//!
+//! Some operations, such as repository maintenance, only need to be done
+//! periodically.  The state file records when these were last done, so
+//! that rdump can be run frequently, and only perform these when they are
+//! due.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::lock::{self, Lock};
+
+/// The default location of the state file.
+pub static DEFAULT_STATE: &str = "/var/lib/rdump/state.yaml";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    /// Maintenance state of each borg repository, indexed by the borg
+    /// script used to access it.
+    #[serde(default)]
+    pub repos: BTreeMap<String, RepoState>,
+    /// Scheduling state of each profile run by `rdump daemon`.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileState>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RepoState {
+    /// The last successful `borg check`.
+    pub last_check: Option<DateTime<Utc>>,
+    /// The last successful `borg check --verify-data`.
+    pub last_verify: Option<DateTime<Utc>>,
+    /// The last successful `borg compact`.
+    pub last_compact: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ProfileState {
+    /// When the profile was last run by the daemon.  The next run is the
+    /// first time in the schedule after this.
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+impl State {
+    /// Load the state file.  A missing file is treated as an empty state.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<State> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_yaml::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the state file.  The new state is written to a temporary file
+    /// and renamed over the old one, so that an interruption will never
+    /// leave a partially written state.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        serde_yaml::to_writer(File::create(&tmp)?, self)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Change the state file.  The state is loaded, changed, and saved
+    /// while holding a lock of its own, so that separate processes, such
+    /// as the daemon and `rdump maintain`, don't lose each other's
+    /// changes.  This lock is only held briefly, unlike the run lock.
+    pub fn update<P, F>(path: P, change: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut State),
+    {
+        let path = path.as_ref();
+        let _lock = Lock::wait(lock::with_suffix(path, ".lock"), None)?;
+        let mut state = State::load(path)?;
+        change(&mut state);
+        state.save(path)
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Reading surefiles.
+//!
+//! An rsure scan records the attributes of every file in a tree, including
+//! a hash of its contents, as a new version in the surefile.  Loading two
+//! versions, or the surefiles of two trees, and comparing them shows which
+//! files differ.
+
+use anyhow::Result;
+use rsure::{AttMap, Store, SureNode, Version};
+use std::collections::BTreeMap;
+
+/// The files of a tree, by their path relative to its root.
+pub type Files = BTreeMap<String, AttMap>;
+
+/// Attributes that change without the file itself changing, such as when
+/// it is restored.
+static VOLATILE: &[&str] = &["ctime", "ino"];
+
+/// Load the files recorded in a version of a surefile.
+pub fn load(store: &dyn Store, version: Version) -> Result<Files> {
+    let mut files = BTreeMap::new();
+    let mut dirs: Vec<String> = vec![];
+    for node in store.load_iter(version)? {
+        match node? {
+            SureNode::Enter { name, .. } => {
+                let path = match dirs.last() {
+                    // The first directory is the root of the tree.
+                    None => String::new(),
+                    Some(parent) => join(parent, &name),
+                };
+                dirs.push(path);
+            }
+            SureNode::Leave => {
+                dirs.pop();
+            }
+            SureNode::File { name, atts } => {
+                let parent = dirs.last().map(|d| d.as_str()).unwrap_or_default();
+                files.insert(join(parent, &name), atts);
+            }
+            SureNode::Sep => (),
+        }
+    }
+    Ok(files)
+}
+
+/// The files that differ between two trees.
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl Changes {
+    /// Compare the files of the `old` tree with the `new` one.
+    pub fn between(old: &Files, new: &Files) -> Changes {
+        let mut changes = Changes::default();
+        for (path, atts) in new {
+            match old.get(path) {
+                None => changes.added.push(path.clone()),
+                Some(old_atts) if !same(old_atts, atts) => changes.modified.push(path.clone()),
+                Some(_) => (),
+            }
+        }
+        for path in old.keys() {
+            if !new.contains_key(path) {
+                changes.removed.push(path.clone());
+            }
+        }
+        changes
+    }
+
+    /// The number of files that differ.
+    pub fn count(&self) -> usize {
+        self.added.len() + self.removed.len() + self.modified.len()
+    }
+}
+
+/// Do the attributes describe the same file?
+fn same(a: &AttMap, b: &AttMap) -> bool {
+    fn stable(atts: &AttMap) -> impl Iterator<Item = (&String, &String)> {
+        atts.iter().filter(|(k, _)| !VOLATILE.contains(&k.as_str()))
+    }
+    stable(a).eq(stable(b))
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Systemd units.
+//!
+//! Rather than writing the units to run scheduled backups by hand, they
+//! are generated from the profiles in the config.  A single template
+//! service, `rdump@.service`, runs the profile named by its instance, and
+//! each profile with a schedule gets a timer, `rdump@<profile>.timer`,
+//! which starts the service of the same instance.
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{checked::CheckedExt, period::Period, schedule::Schedule};
+
+/// The directory units are installed into by default.
+pub static DEFAULT_DIR: &str = "/etc/systemd/system";
+
+static SERVICE: &str = "rdump@.service";
+
+/// Settings for the generated service.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Service {
+    /// The rdump to run.  Defaults to the one generating the units.
+    pub binary: Option<String>,
+    /// The niceness to run backups at.
+    pub nice: Option<i32>,
+    /// The IO scheduling class: `realtime`, `best-effort`, or `idle`.
+    pub io_class: Option<String>,
+}
+
+/// A timer for a scheduled profile.
+pub struct Timer<'a> {
+    pub profile: &'a str,
+    pub schedule: &'a Schedule,
+    /// A random delay added to each run, to spread out the load of
+    /// several hosts.
+    pub randomized_delay: Option<Period>,
+}
+
+/// Write the units for the given timers into `dir`.  With `pretend`, the
+/// units are printed instead.
+pub fn generate(
+    service: &Service,
+    config: &Path,
+    timers: &[Timer],
+    dir: &Path,
+    pretend: bool,
+) -> Result<()> {
+    let config = fs::canonicalize(config)?;
+    let binary = match service.binary {
+        Some(ref binary) => binary.into(),
+        None => env::current_exe()?,
+    };
+
+    let mut units = vec![];
+
+    let mut text = String::new();
+    text.push_str(&format!(
+        "# Generated by `rdump systemd generate` from {}\n",
+        config.display()
+    ));
+    text.push_str("[Unit]\n");
+    text.push_str("Description=rdump backup of profile %i\n");
+    text.push_str("Wants=network-online.target\n");
+    text.push_str("After=local-fs.target network-online.target\n");
+    text.push_str("\n[Service]\n");
+    text.push_str("Type=oneshot\n");
+    text.push_str(&format!(
+        "ExecStart={} -c {} backup --wait --profile %i\n",
+        quote_exec(&binary.to_string_lossy()),
+        quote_exec(&config.to_string_lossy())
+    ));
+    // Only signal rdump itself when stopping, so that it can stop the
+    // running action, and perform the cleanups.
+    text.push_str("KillMode=mixed\n");
+    if let Some(nice) = service.nice {
+        text.push_str(&format!("Nice={}\n", nice));
+    }
+    if let Some(ref class) = service.io_class {
+        text.push_str(&format!("IOSchedulingClass={}\n", class));
+    }
+    units.push((SERVICE.to_string(), text));
+
+    for timer in timers {
+        if !timer
+            .profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!(
+                "Profile {:?} can't be used as a unit name",
+                timer.profile
+            ));
+        }
+        let mut text = String::new();
+        text.push_str(&format!(
+            "# Generated by `rdump systemd generate` from {}\n",
+            config.display()
+        ));
+        text.push_str("[Unit]\n");
+        text.push_str(&format!(
+            "Description=Scheduled rdump backup of profile {}\n",
+            timer.profile
+        ));
+        text.push_str("\n[Timer]\n");
+        text.push_str(&format!("OnCalendar={}\n", timer.schedule));
+        text.push_str("Persistent=true\n");
+        if let Some(delay) = timer.randomized_delay {
+            text.push_str(&format!(
+                "RandomizedDelaySec={}\n",
+                delay.duration().num_seconds()
+            ));
+        }
+        text.push_str("\n[Install]\n");
+        text.push_str("WantedBy=timers.target\n");
+        units.push((timer_unit(timer.profile), text));
+    }
+
+    for (name, text) in &units {
+        let path = dir.join(name);
+        if pretend {
+            println!("==> {} <==", path.display());
+            print!("{}", text);
+            println!();
+        } else {
+            fs::write(&path, text)?;
+            println!("Wrote {}", path.display());
+        }
+    }
+
+    if !pretend {
+        println!("Activate the timers with:");
+        println!("  systemctl daemon-reload");
+        for timer in timers {
+            println!("  systemctl enable --now {}", timer_unit(timer.profile));
+        }
+    }
+    Ok(())
+}
+
+/// Show when each timer last triggered, and will next trigger.
+pub fn status(timers: &[Timer]) -> Result<()> {
+    println!(
+        "{:<12} {:<20} {:<10} {:<28} next",
+        "profile", "schedule", "state", "last"
+    );
+    for timer in timers {
+        let props = show(&timer_unit(timer.profile))?;
+        let prop = |name: &str| match props.get(name).map(|p| p.as_str()) {
+            None | Some("") | Some("n/a") => "-".to_string(),
+            Some(value) => value.to_string(),
+        };
+        let (state, next) = if props.get("LoadState").map(|s| s.as_str()) == Some("loaded") {
+            (prop("ActiveState"), prop("NextElapseUSecRealtime"))
+        } else {
+            // Show when it would run, if it were installed.
+            let next = timer.schedule.next_after(Local::now());
+            (
+                "missing".to_string(),
+                next.format("%a %Y-%m-%d %H:%M:%S %Z").to_string(),
+            )
+        };
+        println!(
+            "{:<12} {:<20} {:<10} {:<28} {}",
+            timer.profile,
+            timer.schedule.to_string(),
+            state,
+            prop("LastTriggerUSec"),
+            next
+        );
+    }
+    Ok(())
+}
+
+/// The name of the timer unit for a profile.
+fn timer_unit(profile: &str) -> String {
+    format!("rdump@{}.timer", profile)
+}
+
+/// Quote an argument of a command line in a unit, so that systemd passes
+/// it as it is, without splitting it, or expanding specifiers (`%`) or
+/// environment variables (`$`).
+fn quote_exec(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in arg.chars() {
+        match ch {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Query the properties of a unit from systemd.
+fn show(unit: &str) -> Result<BTreeMap<String, String>> {
+    let out = Command::new("systemctl")
+        .args([
+            "show",
+            unit,
+            "--property=LoadState,ActiveState,LastTriggerUSec,NextElapseUSecRealtime",
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .checked_output()?;
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn units() {
+        let dir = env::temp_dir().join(format!("rdump-test-{}-systemd", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("rdump.yaml");
+        fs::write(&config, "").unwrap();
+        let config = fs::canonicalize(&config).unwrap();
+
+        let service = Service {
+            binary: Some("/usr/local/bin/rdump".into()),
+            nice: Some(10),
+            io_class: Some("idle".into()),
+        };
+        let (daily, quarter) = ("daily".parse().unwrap(), "*:15".parse().unwrap());
+        let timers = [
+            Timer {
+                profile: "nightly",
+                schedule: &daily,
+                randomized_delay: Some("30m".parse().unwrap()),
+            },
+            Timer {
+                profile: "hourly",
+                schedule: &quarter,
+                randomized_delay: None,
+            },
+        ];
+        generate(&service, &config, &timers, &dir, false).unwrap();
+        let unit = |name| fs::read_to_string(dir.join(name)).unwrap();
+        let (service, nightly, hourly) = (
+            unit(SERVICE),
+            unit("rdump@nightly.timer"),
+            unit("rdump@hourly.timer"),
+        );
+
+        let bad = [Timer {
+            profile: "night/ly",
+            schedule: &daily,
+            randomized_delay: None,
+        }];
+        let err = generate(&Service::default(), &config, &bad, &dir, true).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let header = format!(
+            "# Generated by `rdump systemd generate` from {}\n",
+            config.display()
+        );
+        assert_eq!(
+            service,
+            format!(
+                "{}[Unit]
+Description=rdump backup of profile %i
+Wants=network-online.target
+After=local-fs.target network-online.target
+
+[Service]
+Type=oneshot
+ExecStart=\"/usr/local/bin/rdump\" -c \"{}\" backup --wait --profile %i
+KillMode=mixed
+Nice=10
+IOSchedulingClass=idle
+",
+                header,
+                config.display()
+            )
+        );
+        assert_eq!(
+            nightly,
+            format!(
+                "{}[Unit]
+Description=Scheduled rdump backup of profile nightly
+
+[Timer]
+OnCalendar=daily
+Persistent=true
+RandomizedDelaySec=1800
+
+[Install]
+WantedBy=timers.target
+",
+                header
+            )
+        );
+        assert_eq!(
+            hourly,
+            format!(
+                "{}[Unit]
+Description=Scheduled rdump backup of profile hourly
+
+[Timer]
+OnCalendar=*:15
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+",
+                header
+            )
+        );
+        assert_eq!(
+            err.to_string(),
+            "Profile \"night/ly\" can't be used as a unit name"
+        );
+    }
+
+    #[test]
+    fn quoted_paths() {
+        let dir = env::temp_dir().join(format!("rdump-test-{}-systemd quoted", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("rdump 100%.yaml");
+        fs::write(&config, "").unwrap();
+        let config = fs::canonicalize(&config).unwrap();
+
+        let service = Service {
+            binary: Some("/opt/my \"backups\"/$HOME\\rdump".into()),
+            ..Service::default()
+        };
+        generate(&service, &config, &[], &dir, false).unwrap();
+        let text = fs::read_to_string(dir.join(SERVICE)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let exec = text.lines().find(|l| l.starts_with("ExecStart=")).unwrap();
+        assert_eq!(
+            exec,
+            format!(
+                "ExecStart=\"/opt/my \\\"backups\\\"/$$HOME\\\\rdump\" -c \"{}/rdump 100%%.yaml\" \
+                 backup --wait --profile %i",
+                dir.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Backup timestamps.
+//!
+//! Each run of rdump names its borg archives and ZFS snapshots with a
+//! timestamp of when the run started, in UTC.  This is what ties the
+//! different kinds of backups of a volume together.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+/// The format of the timestamps used in backup names.
+pub static FORMAT: &str = "%Y%m%dT%H%M%S";
+
+/// Generate a timestamp for a new backup.
+pub fn now() -> String {
+    format!("{}", Utc::now().format(FORMAT))
+}
+
+/// Decode a timestamp generated by `now`.
+pub fn parse(text: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(text, FORMAT)
+        .ok()
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
+/// Decode a timestamp from a backup name of the form `name-timestamp`, or
+/// `name-timestamp.N` for a retried borg create.  Returns None if the name
+/// is for a different volume, or has no valid timestamp.
+pub fn parse_named(volume: &str, text: &str) -> Option<DateTime<Utc>> {
+    let rest = text.strip_prefix(volume)?.strip_prefix('-')?;
+    let time = match rest.split_once('.') {
+        Some((time, attempt)) if attempt.parse::<u32>().is_ok() => time,
+        _ => rest,
+    };
+    parse(time)
+}
+
+/// Parse a time given by a user.  Accepts the backup timestamp format
+/// (which is UTC), RFC 3339, or a local date with an optional time, such
+/// as `2021-02-27` or `2021-02-27 10:15`.  A date on its own refers to the
+/// end of that day.
+pub fn parse_user(text: &str) -> Result<DateTime<Utc>> {
+    if let Some(t) = parse(text) {
+        return Ok(t);
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(text) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let local = if let Ok(t) = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S") {
+        t
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M") {
+        t
+    } else if let Some(t) = NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(23, 59, 59))
+    {
+        t
+    } else {
+        return Err(anyhow!("Unable to parse time {:?}", text));
+    };
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("Invalid local time {:?}", text))
+}
@@ -5,18 +5,28 @@
 #![allow(unused)]
 
 use anyhow::{anyhow, Result};
-use chrono::{Datelike, Local, Timelike};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+use log::warn;
 use regex::{self, Regex};
 use serde::Serialize;
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::HashMap,
     fs::File,
     io::{self, BufRead, BufReader},
-    os::unix::io::{AsRawFd, FromRawFd},
     process::{Command, Stdio},
 };
 
-use crate::checked::CheckedExt;
+use self::pipeline::Pipeline;
+use crate::{
+    cancel,
+    checked::CheckedExt,
+    retention::{self, Retention},
+    timestamp,
+};
+
+pub use self::pipeline::Compression;
+
+mod pipeline;
 
 // This is an assumption, which seems to be true on at least Fedora and
 // Gentoo installs of ZFS.
@@ -39,7 +49,70 @@ pub struct Zfs {
 pub struct Filesystem {
     pub name: String,
     pub snaps: Vec<String>,
+    /// The bookmarks, which remain of snapshots that have been pruned.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bookmarks: Vec<String>,
+    /// The snapshots that have holds on them, and so can't be destroyed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub held: Vec<String>,
     pub mount: String,
+    /// The token to resume an interrupted receive into this filesystem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+    #[serde(flatten)]
+    pub marks: Marks,
+}
+
+/// How a filesystem has been marked for backup, with `org.rdump:` user properties.  As user
+/// properties are inherited, marking a filesystem also marks those under it, unless they are
+/// marked differently themselves.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Marks {
+    /// `org.rdump:backup`, opting the filesystem in to (`on`), or out of (`off`), replication.
+    /// Once any filesystem under a replication's source has opted in, only those that have are
+    /// replicated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<bool>,
+    /// `org.rdump:exclude=true`, leaving the filesystem out of both replication and pruning,
+    /// like matching one of the `excludes`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub exclude: bool,
+    /// `org.rdump:retention`, a retention policy written as a yaml mapping, such as `{daily: 7,
+    /// weekly: 4}`, used to prune the filesystem instead of the configured one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<String>,
+}
+
+/// Options for how filesystems are sent.
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Send encrypted filesystems as they are stored (`zfs send -w`), so that they are never
+    /// decrypted on the way to, or on, the destination.
+    pub raw: bool,
+    /// Send blocks compressed as they are on disk (`-c`).
+    pub compressed: bool,
+    /// Allow blocks larger than 128KiB (`-L`).
+    pub large_blocks: bool,
+    /// Send embedded data blocks as they are (`-e`).
+    pub embedded: bool,
+    /// Buffer the stream with mbuffer, of this size, such as `1G`.
+    pub buffer: Option<String>,
+    /// Compress the stream while it crosses ssh to or from another host.
+    pub compress: Option<Compression>,
+}
+
+/// The data sent by a `zfs send`.
+enum Stream<'a> {
+    /// A full stream of the given snapshot.
+    Full(&'a str),
+    /// An incremental stream of every snapshot after the first, up to and
+    /// including the second.
+    Incremental(&'a str, &'a str),
+    /// An incremental stream from the bookmark to the snapshot, without any
+    /// snapshots between them.
+    FromBookmark(&'a str, &'a str),
+    /// The remainder of an interrupted stream, given its resume token.
+    Resume(&'a str),
 }
 
 impl Zfs {
@@ -49,41 +122,22 @@ impl Zfs {
         let pat = format!("^{}(\\d{{4}})-([-\\d]+)$", quoted);
         let re = Regex::new(&pat)?;
 
-        // Ask ZFS what all of the Filesystems are that it knows about.  Just get the names and
-        // mountpoints (which will include all snapshots).  Order of the volumes seems to mostly be
-        // lexicographically, at least in some kind of tree order.  The snapshots come out in the
-        // order they were created.
-        let mut cmd = match host {
-            None => Command::new(ZFS),
-            Some(host) => {
-                let mut cmd = Command::new("ssh");
-                cmd.args(&[host, "sudo", ZFS]);
-                cmd
-            }
-        };
-        let out = cmd
-            .args(&["list", "-H", "-t", "all", "-o", "name,mountpoint"])
+        let marks = Marks::read(host)?;
+
+        // Ask ZFS what all of the Filesystems are that it knows about.  Just get the names,
+        // mountpoints, resume tokens, and number of holds (which will include all snapshots).
+        let out = command(host)
+            .args(&[
+                "list",
+                "-H",
+                "-t",
+                "all",
+                "-o",
+                "name,receive_resume_token,userrefs,mountpoint",
+            ])
             .stderr(Stdio::inherit())
             .checked_output()?;
-        let buf = out.stdout;
-
-        let mut builder = SnapBuilder::new();
-
-        for line in BufReader::new(&buf[..]).lines() {
-            let line = line?;
-            let fields: Vec<_> = line.splitn(2, '\t').collect();
-            if fields.len() != 2 {
-                return Err(anyhow!("zfs line doesn't have two fields: {:?}", line));
-            }
-            // fields[0] is now the volume/snap name, and fields[1] is the mountpoint.
-            let vols: Vec<_> = fields[0].splitn(2, '@').collect();
-            match vols.len() {
-                1 => builder.push_volume(vols[0], fields[1]),
-                2 => builder.push_snap(vols[0], vols[1]),
-                _ => panic!("Unexpected zfs output"),
-            }
-        }
-        let result = builder.into_sets();
+        let result = parse_list(&out.stdout, marks)?;
 
         Ok(Zfs {
             prefix: prefix.to_string(),
@@ -152,12 +206,9 @@ impl Zfs {
     /// Make a new snapshot of the given index on the given filesystem name.  The snapshot itself
     /// will be made recursively.
     pub fn take_snapshot(&self, fs: &str, index: usize) -> Result<()> {
-        if self.host.is_some() {
-            return Err(anyhow!("Only local snapshots supported"));
-        }
         let name = format!("{}@{}", fs, self.snap_name(index));
-        println!("Make snapshot: {}", name);
-        Command::new(ZFS)
+        println!("Make snapshot: {}{}", self.host_prefix(), name);
+        self.command()
             .args(&["snapshot", "-r", &name])
             .stderr(Stdio::inherit())
             .checked_run()?;
@@ -166,11 +217,8 @@ impl Zfs {
 
     /// Make a new snapshot, of a given name.
     pub fn take_named_snapshot(&self, fs: &str, name: &str) -> Result<()> {
-        if self.host.is_some() {
-            return Err(anyhow!("Only local snapshots supported"));
-        }
         let name = format!("{}@{}", fs, name);
-        Command::new(ZFS)
+        self.command()
             .args(&["snapshot", &name])
             .stderr(Stdio::inherit())
             .checked_run()?;
@@ -179,6 +227,7 @@ impl Zfs {
 
     /// Clone one volume tree to another.  Perform should be set to true to
     /// actually do the clones, otherwise it just prints what it would do.
+    /// Returns the estimated number of bytes sent.
     pub fn clone(
         &self,
         source: &str,
@@ -186,12 +235,19 @@ impl Zfs {
         dest_zfs: &Zfs,
         perform: bool,
         excludes: &[&str],
-    ) -> Result<()> {
+        options: &SendOptions,
+    ) -> Result<usize> {
         let excludes = Exclusions::new(excludes)?;
+        let mut total = 0;
+
+        // The newest snapshot sent to the destination is held on the source, so that pruning
+        // can't remove the base of the next incremental send.
+        let tag = format!("rdump-{}{}", dest_zfs.host_prefix(), dest);
 
         // Get filtered views of the source and destination filesystems under the given trees.
         let source_fs = self.filtered(source)?;
         let dest_fs = dest_zfs.filtered(dest)?;
+        let opt_in = source_fs.iter().any(|fs| fs.marks.backup == Some(true));
 
         // Make a mapping between the suffixes of the names (including the empty string for one
         // that exactly matches `dest`.  This should be safe as long as `.filtered()` above
@@ -202,20 +258,15 @@ impl Zfs {
             .collect();
 
         for src in &source_fs {
-            if excludes.is_excluded(&src.name) {
+            if excludes.is_excluded(&src.name) || !src.marks.wanted(opt_in) {
                 // println!("Skip: {:?}", src.name);
                 continue;
             }
 
-            // Don't clone bookmarks.
-            if src.name.contains('#') {
-                continue;
-            }
-
             match dest_map.get(&src.name[source.len()..]) {
                 Some(d) => {
                     println!("Clone existing: {:?} to {:?}", src.name, d.name);
-                    self.clone_one(src, d, dest_zfs, perform)?;
+                    total += self.clone_one(src, d, dest_zfs, perform, options)?;
                     if !perform {
                         println!("Clone from:");
                         serde_yaml::to_writer(io::stdout().lock(), src)?;
@@ -237,13 +288,19 @@ impl Zfs {
                     let destfs = Filesystem {
                         name: format!("{}{}", dest, &src.name[source.len()..]),
                         snaps: vec![],
+                        bookmarks: vec![],
+                        held: vec![],
                         mount: "*INVALID*".into(),
+                        resume_token: None,
+                        marks: Marks::default(),
                     };
 
-                    if perform {
-                        self.make_volume(src, &destfs)?;
+                    // A raw send of an encrypted volume must create the volume itself, so
+                    // that it is received as its own encryption root, with the source's keys.
+                    if perform && !(options.raw && self.encrypted(&src.name)?) {
+                        self.make_volume(src, &destfs, dest_zfs)?;
                     }
-                    self.clone_one(src, &destfs, dest_zfs, perform)?;
+                    total += self.clone_one(src, &destfs, dest_zfs, perform, options)?;
                     if !perform {
                         println!("Clone from:");
                         serde_yaml::to_writer(io::stdout().lock(), src)?;
@@ -254,24 +311,74 @@ impl Zfs {
                     }
                 }
             }
+
+            if perform {
+                self.hold_base(src, &tag)?;
+            }
         }
 
-        Ok(())
+        Ok(total)
     }
 
     /// Clone a single filesystem to an existing volume.  We assume there are no snapshots on the
-    /// destination that aren't on the source (otherwise it isn't possible to do the clone).
+    /// destination that aren't on the source (otherwise it isn't possible to do the clone).  If
+    /// an earlier receive into the destination was interrupted, it is resumed first.  Returns the
+    /// estimated size of the data sent.
     fn clone_one(
         &self,
         source: &Filesystem,
         dest: &Filesystem,
         dest_zfs: &Zfs,
         perform: bool,
-    ) -> Result<()> {
-        if let Some(ssnap) = dest.snaps.last() {
-            if !source.snaps.contains(ssnap) {
-                return Err(anyhow!("Last dest snapshot not present in source"));
+        options: &SendOptions,
+    ) -> Result<usize> {
+        let mut total = 0;
+        let mut last = dest.snaps.last().cloned();
+
+        // A raw send into a destination that was received decrypted would fail, and sending
+        // decrypted instead would defeat the purpose.
+        let received = last.is_some() || dest.resume_token.is_some();
+        if options.raw
+            && received
+            && self.encrypted(&source.name)?
+            && !dest_zfs.encrypted(&dest.name)?
+        {
+            return Err(anyhow!(
+                "{} is encrypted, but {} is not, so it can't receive a raw send",
+                source.name,
+                dest.name
+            ));
+        }
+
+        if let Some(ref token) = dest.resume_token {
+            let (snap, size) = self.estimate_resume(token).map_err(|e| {
+                anyhow!(
+                    "Unable to resume receive into {} ({:#}), `zfs receive -A {}` will discard it",
+                    dest.name,
+                    e,
+                    dest.name
+                )
+            })?;
+            println!(
+                "Partially received: {}, resume from {}@{}",
+                dest.name, source.name, snap
+            );
+            println!("Estimate: {}", humanize_size(size));
+            if perform {
+                self.do_clone(
+                    &source.name,
+                    &dest.name,
+                    Stream::Resume(token),
+                    options,
+                    dest_zfs,
+                    size,
+                )?;
             }
+            total += size;
+            last = Some(snap);
+        }
+
+        if let Some(ref ssnap) = last {
             let dsnap = if let Some(dsnap) = source.snaps.last() {
                 dsnap
             } else {
@@ -280,29 +387,38 @@ impl Zfs {
 
             if dsnap == ssnap {
                 println!("Destination is up to date");
-                return Ok(());
+                return Ok(total);
             }
 
-            println!(
-                "Clone from {}@{} to {}@{}",
-                source.name, ssnap, dest.name, dsnap
-            );
+            let stream = if source.snaps.contains(ssnap) {
+                println!(
+                    "Clone from {}@{} to {}@{}",
+                    source.name, ssnap, dest.name, dsnap
+                );
+                Stream::Incremental(ssnap, dsnap)
+            } else if source.bookmarks.contains(ssnap) {
+                // The snapshot has been pruned from the source, but its bookmark can still be
+                // sent from.  Bookmarks aren't ordered amongst the snapshots, so only the newest
+                // snapshot can safely be sent this way, and those between are skipped.
+                println!(
+                    "Clone from {}#{} to {}@{}",
+                    source.name, ssnap, dest.name, dsnap
+                );
+                Stream::FromBookmark(ssnap, dsnap)
+            } else {
+                return Err(anyhow!(
+                    "Last dest snapshot not present in source, as a snapshot or bookmark"
+                ));
+            };
 
-            let size = self.estimate_size(&source.name, Some(ssnap), dsnap)?;
+            let size = self.estimate_size(&source.name, &stream, options)?;
             println!("Estimate: {}", humanize_size(size));
 
             if perform {
-                self.do_clone(
-                    &source.name,
-                    &dest.name,
-                    Some(ssnap),
-                    dsnap,
-                    &dest_zfs,
-                    size,
-                )?;
+                self.do_clone(&source.name, &dest.name, stream, options, &dest_zfs, size)?;
             }
 
-            Ok(())
+            Ok(total + size)
         } else {
             // When doing a full clone, clone from the first snapshot of the volume, and then do a
             // differential backup from that snapshot.
@@ -314,9 +430,13 @@ impl Zfs {
 
             println!("Full clone from {}@{} to {}", source.name, dsnap, dest.name);
 
-            let size = self.estimate_size(&source.name, None, dsnap)?;
+            let stream = Stream::Full(dsnap);
+            let size = self.estimate_size(&source.name, &stream, options)?;
             println!("Estimate: {}", humanize_size(size));
-            self.do_clone(&source.name, &dest.name, None, dsnap, &dest_zfs, size)?;
+            if perform {
+                self.do_clone(&source.name, &dest.name, stream, options, &dest_zfs, size)?;
+            }
+            total += size;
 
             // Run the clone on the rest of the image.
             let ssnap = dsnap;
@@ -324,34 +444,73 @@ impl Zfs {
 
             // If there are more snapshots to make, clone the rest.
             if ssnap != dsnap {
-                let size = self.estimate_size(&source.name, Some(ssnap), dsnap)?;
+                let stream = Stream::Incremental(ssnap, dsnap);
+                let size = self.estimate_size(&source.name, &stream, options)?;
                 if perform {
-                    self.do_clone(
-                        &source.name,
-                        &dest.name,
-                        Some(ssnap),
-                        dsnap,
-                        &dest_zfs,
-                        size,
-                    )?;
+                    self.do_clone(&source.name, &dest.name, stream, options, &dest_zfs, size)?;
                 }
+                total += size;
             }
 
-            Ok(())
+            Ok(total)
         }
     }
 
-    /// Use zfs send to estimate the size of this incremental backup.  If the source snap is none,
-    /// operate as a full clone.
-    fn estimate_size(&self, source: &str, ssnap: Option<&str>, dsnap: &str) -> Result<usize> {
-        let mut cmd = Command::new(ZFS);
+    /// Hold the newest snapshot of `fs` with the tag, which is now the base for sending to the
+    /// tag's destination, and release the tag from the snapshot that was the base before.
+    fn hold_base(&self, fs: &Filesystem, tag: &str) -> Result<()> {
+        let newest = match fs.snaps.last() {
+            Some(newest) => newest,
+            None => return Ok(()),
+        };
+        let holds = self.holds(fs)?;
+        if !holds.iter().any(|(snap, t)| snap == newest && t == tag) {
+            self.command()
+                .args(&["hold", tag, &format!("{}@{}", fs.name, newest)])
+                .stderr(Stdio::inherit())
+                .checked_run()?;
+        }
+        for (snap, _) in holds.iter().filter(|(snap, t)| snap != newest && t == tag) {
+            self.command()
+                .args(&["release", tag, &format!("{}@{}", fs.name, snap)])
+                .stderr(Stdio::inherit())
+                .checked_run()?;
+        }
+        Ok(())
+    }
+
+    /// Return the holds on the snapshots of `fs`, as the snapshot and the tag of each.
+    fn holds(&self, fs: &Filesystem) -> Result<Vec<(String, String)>> {
+        if fs.held.is_empty() {
+            return Ok(vec![]);
+        }
+        let out = self
+            .command()
+            .args(&["holds", "-H"])
+            .args(fs.held.iter().map(|snap| format!("{}@{}", fs.name, snap)))
+            .stderr(Stdio::inherit())
+            .checked_output()?;
+        let mut holds = vec![];
+        for line in BufReader::new(&out.stdout[..]).lines() {
+            let line = line?;
+            let fields: Vec<_> = line.split('\t').collect();
+            if fields.len() < 2 {
+                return Err(anyhow!("Invalid line from zfs holds: {:?}", line));
+            }
+            let snap = fields[0].split_once('@').map(|(_, snap)| snap);
+            let snap =
+                snap.ok_or_else(|| anyhow!("Invalid snapshot from zfs holds: {:?}", line))?;
+            holds.push((snap.to_owned(), fields[1].to_owned()));
+        }
+        Ok(holds)
+    }
+
+    /// Use zfs send to estimate the size of this backup.
+    fn estimate_size(&self, source: &str, stream: &Stream, options: &SendOptions) -> Result<usize> {
+        let mut cmd = self.command();
         cmd.arg("send");
         cmd.arg("-nP");
-        if let Some(ssnap) = ssnap {
-            cmd.arg("-I");
-            cmd.arg(&format!("@{}", ssnap));
-        }
-        cmd.arg(&format!("{}@{}", source, dsnap));
+        cmd.args(stream.args(source, options));
         cmd.stderr(Stdio::inherit());
         let out = cmd.checked_output()?;
 
@@ -375,132 +534,137 @@ impl Zfs {
         Ok(0)
     }
 
+    /// Use zfs send to find what resuming with the given token will send.  Returns the name of
+    /// the snapshot being sent, and the estimated size of the rest of it.
+    fn estimate_resume(&self, token: &str) -> Result<(String, usize)> {
+        // The verbose output starts with the contents of the token, as "name = value" lines, and
+        // then gives the estimate in the same form as for other sends.
+        let out = self
+            .command()
+            .args(&["send", "-nvP", "-t", token])
+            .stderr(Stdio::inherit())
+            .checked_output()?;
+
+        let mut snap = None;
+        let mut size = 0;
+        for line in BufReader::new(&out.stdout[..]).lines() {
+            let line = line?;
+            if let Some(name) = line.trim().strip_prefix("toname = ") {
+                snap = name.split_once('@').map(|(_, snap)| snap.to_owned());
+            } else if let Some(value) = line.strip_prefix("size\t") {
+                size = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid size from zfs send: {:?}", line))?;
+            }
+        }
+        let snap = snap.ok_or_else(|| anyhow!("Resume token doesn't name a snapshot"))?;
+        Ok((snap, size))
+    }
+
     /// Perform the actual clone.
     fn do_clone(
         &self,
         source: &str,
         dest: &str,
-        ssnap: Option<&str>,
-        dsnap: &str,
+        stream: Stream,
+        options: &SendOptions,
         dest_zfs: &Zfs,
         size: usize,
     ) -> Result<()> {
-        // Construct a pipeline from zfs -> pv -> zfs.  PV is used to monitor the progress.
-        let mut cmd = Command::new(ZFS);
-        cmd.arg("send");
-        if let Some(ssnap) = ssnap {
-            cmd.arg("-I");
-            cmd.arg(&format!("@{}", ssnap));
-        }
-        cmd.arg(&format!("{}@{}", source, dsnap));
-        cmd.stderr(Stdio::inherit());
-        cmd.stdout(Stdio::piped());
-        let mut sender = cmd.spawn()?;
-
-        let send_out = sender.stdout.as_ref().expect("Child output").as_raw_fd();
-
-        // The unsafe is because using raw descriptors could make them available after they are
-        // closed.  These are being given to a spawn, which will be inherited by a fork, and is
-        // safe.
-        let mut pv = Command::new("pv")
-            .args(&["-s", &size.to_string()])
-            .stdin(unsafe { Stdio::from_raw_fd(send_out) })
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
-
-        let pv_out = pv.stdout.as_ref().expect("PV output").as_raw_fd();
-
-        let mut cmd = match &dest_zfs.host {
-            None => Command::new(ZFS),
-            Some(host) => {
-                let mut cmd = Command::new("ssh");
-                cmd.args(&[host, "sudo", ZFS]);
-                cmd
-            }
-        };
-        let mut receiver = cmd
-            .args(&["receive", "-vF", "-x", "mountpoint", dest])
-            .stdin(unsafe { Stdio::from_raw_fd(pv_out) })
-            .stderr(Stdio::inherit())
-            .spawn()?;
-
-        // pv -s <size>
-        // zfs receive -vFu <dest>
-
-        if !sender.wait()?.success() {
-            return Err(anyhow!("zfs send error"));
-        }
-        if !pv.wait()?.success() {
-            return Err(anyhow!("pv error"));
-        }
-        if !receiver.wait()?.success() {
-            return Err(anyhow!("zfs receive error"));
-        }
-
-        Ok(())
+        // The receive is resumable, so that if it is interrupted, the next clone can pick up
+        // where it left off, rather than starting over.
+        let receive = ["-s", "-vF", "-x", "mountpoint", dest];
+        Pipeline::transfer(
+            self.host.as_deref(),
+            self.argv("send", stream.args(source, options)),
+            dest_zfs.host.as_deref(),
+            dest_zfs.argv("receive", receive.iter().map(|a| a.to_string()).collect()),
+            options,
+            size,
+        )
+        .run()
     }
 
-    /// Prune old snapshots.  This is a Hanoi-type pruning model, where we keep the most recent
-    /// snapshot that has the same number of bits set in it.  In addition, we keep a certain number
-    /// `PRUNE_KEEP` of the most recent snapshots.
-    pub fn prune_hanoi(&self, fs_name: &str, really: bool) -> Result<()> {
-        let fs = if let Some(fs) = self.filesystems.iter().find(|fs| fs.name == fs_name) {
-            fs
-        } else {
-            return Err(anyhow!("Volume not found in zfs {:?}", fs_name));
-        };
-
-        // Get all of the snapshots, oldest first, that match this tag, and pair them up with
-        // the decoded number.
-        let mut snaps: Vec<_> = fs
-            .snaps
-            .iter()
-            .filter_map(|sn| self.snap_number(sn).map(|num| (sn, num)))
-            .collect();
-        snaps.reverse();
-
-        let mut pops = BTreeSet::<u32>::new();
-        let mut to_prune = vec![];
+    /// Prune the snapshots of each filesystem under `under` (other than those excluded)
+    /// according to the retention policy.  Snapshots with holds are never pruned.  Only prints
+    /// what would be pruned, unless `really`.
+    pub fn prune_tree(
+        &self,
+        under: &str,
+        retention: &Retention,
+        excludes: &[&str],
+        really: bool,
+    ) -> Result<()> {
+        let excludes = Exclusions::new(excludes)?;
+        let now = Utc::now();
 
-        for item in snaps.iter().enumerate() {
-            // Don't prune the most recent ones.
-            let index = item.0;
-            if index < PRUNE_KEEP {
+        for fs in self.filtered(under)? {
+            if excludes.is_excluded(&fs.name) || fs.marks.exclude {
                 continue;
             }
-
-            let name = (item.1).0;
-            let num = (item.1).1;
-
-            let bit_count = num.count_ones();
-            if pops.contains(&bit_count) {
-                let prune_name = format!("{}@{}", fs_name, name);
-
-                to_prune.push(prune_name);
+            let marked = fs
+                .marks
+                .retention()
+                .map_err(|e| anyhow!("{}: {:#}", fs.name, e))?;
+            let retention = marked.as_ref().unwrap_or(retention);
+
+            let snaps: Vec<_> = fs
+                .snaps
+                .iter()
+                .filter_map(|name| {
+                    self.snap_time(name).map(|time| retention::Snapshot {
+                        name,
+                        time,
+                        number: self.snap_number(name).map(|n| n as u64),
+                    })
+                })
+                .collect();
+            let keep = retention.decide(&snaps, now);
+            let holds = self.holds(fs)?;
+
+            // Prune the oldest first, so that an interruption leaves the most history.
+            for (snap, reason) in snaps.iter().zip(&keep) {
+                match reason {
+                    Some(reason) if !really => {
+                        println!("keep {:?}@{:?} ({})", fs.name, snap.name, reason)
+                    }
+                    Some(_) => (),
+                    None if fs.held.iter().any(|h| h == snap.name) => {
+                        // Held snapshots can't be destroyed, and are usually the base of a
+                        // replication.
+                        let tags: Vec<_> = holds
+                            .iter()
+                            .filter(|(s, _)| s == snap.name)
+                            .map(|(_, tag)| tag.as_str())
+                            .collect();
+                        println!(
+                            "not pruning {:?}@{:?}: held by {}",
+                            fs.name,
+                            snap.name,
+                            tags.join(", ")
+                        );
+                    }
+                    None => self.prune(&fs.name, snap.name, really)?,
+                }
             }
-            pops.insert(bit_count);
         }
 
-        // Now do the actual pruning, starting with the oldest ones.
-        to_prune.reverse();
+        Ok(())
+    }
 
-        for prune_name in &to_prune {
-            println!(
-                "{}prune: {}",
-                if really { "" } else { "would " },
-                prune_name
-            );
-            if really {
-                Command::new(ZFS)
-                    .arg("destroy")
-                    .arg(&prune_name)
-                    .stderr(Stdio::inherit())
-                    .checked_run()?;
-            }
+    /// The time a snapshot was made, from its name.  Snapshots are named with a timestamp, or
+    /// with a number followed by the local time.
+    fn snap_time(&self, name: &str) -> Option<DateTime<Utc>> {
+        if let Some(time) = timestamp::parse(name) {
+            return Some(time);
         }
-
-        Ok(())
+        let caps = self.snap_re.captures(name)?;
+        let local = NaiveDateTime::parse_from_str(caps.get(2)?.as_str(), "%Y%m%d%H%M").ok()?;
+        Local
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc))
     }
 
     /// Prune a single snapshot (possibly, based on `really`).  This will
@@ -509,7 +673,8 @@ impl Zfs {
         if really {
             // Try creating a bookmark.
             println!("pruning: {:?}@{:?}", vol, snap);
-            let status = Command::new(ZFS)
+            let status = self
+                .command()
                 .arg("bookmark")
                 .arg(&format!("{}@{}", vol, snap))
                 .arg(&format!("{}#{}", vol, snap))
@@ -520,7 +685,7 @@ impl Zfs {
             }
 
             // destroy the snapshot
-            Command::new(ZFS)
+            self.command()
                 .arg("destroy")
                 .arg(&format!("{}@{}", vol, snap))
                 .stderr(Stdio::inherit())
@@ -531,11 +696,12 @@ impl Zfs {
         Ok(())
     }
 
-    /// Construct a new volume at "dest".  Copies over certain attributes (acltype, xattr, atime,
-    /// relatime) that are relevant to the snapshot being correct.
-    fn make_volume(&self, src: &Filesystem, dest: &Filesystem) -> Result<()> {
+    /// Construct a new volume at "dest" in `dest_zfs`.  Copies over certain attributes (acltype,
+    /// xattr, atime, relatime) that are relevant to the snapshot being correct.
+    fn make_volume(&self, src: &Filesystem, dest: &Filesystem, dest_zfs: &Zfs) -> Result<()> {
         // Read the attributes from the source volume.
-        let out = Command::new(ZFS)
+        let out = self
+            .command()
             .args(&["get", "-Hp", "all", &src.name])
             .stderr(Stdio::inherit())
             .checked_output()?;
@@ -554,8 +720,9 @@ impl Zfs {
 
             // We care about "local" or "received" properties, which are ones that will be set to a
             // value not present.  But, don't include the 'mountpoint' property, so that the backup
-            // won't have things randomly mounted.
-            if fields[1] == "mountpoint" {
+            // won't have things randomly mounted, or our own properties, which mark the source for
+            // backup, not the backup itself.
+            if fields[1] == "mountpoint" || fields[1].starts_with("org.rdump:") {
                 continue;
             }
             if fields[3] == "local" || fields[3] == "received" {
@@ -565,7 +732,8 @@ impl Zfs {
         }
         println!("   props: {:?}", props);
 
-        Command::new(ZFS)
+        dest_zfs
+            .command()
             .arg("create")
             .args(&props)
             .arg(&dest.name)
@@ -576,8 +744,51 @@ impl Zfs {
     }
 
     pub fn find_mount(&self, name: &str) -> Result<String> {
+        if self.host.is_some() {
+            return Err(anyhow!("Only local volumes can be mounted"));
+        }
         find_mount(name)
     }
+
+    /// Is the named filesystem encrypted?
+    fn encrypted(&self, name: &str) -> Result<bool> {
+        Ok(self.property(name, "encryption")? != "off")
+    }
+
+    /// Retrieve the value of a property of the named filesystem.
+    fn property(&self, name: &str, property: &str) -> Result<String> {
+        let out = self
+            .command()
+            .args(&["get", "-H", "-o", "value", property, name])
+            .stderr(Stdio::inherit())
+            .checked_output()?;
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+    }
+
+    /// The arguments to run a zfs subcommand on the host of this Zfs, as part of a pipeline.
+    fn argv(&self, command: &str, args: Vec<String>) -> Vec<String> {
+        let mut argv = vec![];
+        if self.host.is_some() {
+            argv.push("sudo".to_string());
+        }
+        argv.push(ZFS.to_string());
+        argv.push(command.to_string());
+        argv.extend(args);
+        argv
+    }
+
+    /// Construct a Command to run zfs on the host of this Zfs.
+    fn command(&self) -> Command {
+        command(self.host.as_deref())
+    }
+
+    /// A prefix naming the host, for messages about remote volumes.
+    fn host_prefix(&self) -> String {
+        self.host
+            .as_ref()
+            .map(|h| format!("{}:", h))
+            .unwrap_or_default()
+    }
 }
 
 /// Find where a volume is mounted.  Since Linux can mount ZFS volumes
@@ -598,13 +809,170 @@ pub fn find_mount(name: &str) -> Result<String> {
     return Err(anyhow!("Not mounted {:?}", name));
 }
 
-// Construct a Command appropriate for running a zfs command.  This is
-// based on the hostname, and will possibly run the command remotely for a
-// remove ZFS.  Remote operation only makes sense for some commands.
-// fn build_command(
+/// Decode the output of `zfs list -H -t all -o name,receive_resume_token,userrefs,mountpoint`,
+/// giving each filesystem its marks.  Order of the volumes seems to mostly be lexicographically,
+/// at least in some kind of tree order.  The snapshots come out in the order they were created.
+/// The mountpoint is last, as it is the only field that could contain a tab.
+fn parse_list(buf: &[u8], mut marks: HashMap<String, Marks>) -> Result<Vec<Filesystem>> {
+    let mut builder = SnapBuilder::new();
+
+    for line in BufReader::new(buf).lines() {
+        let line = line?;
+        let fields: Vec<_> = line.splitn(4, '\t').collect();
+        if fields.len() != 4 {
+            return Err(anyhow!("zfs line doesn't have four fields: {:?}", line));
+        }
+        // fields[0] is now the volume/snap name, fields[1] the resume token ("-" for none),
+        // fields[2] the number of holds on a snapshot, and fields[3] is the mountpoint.
+        let token = match fields[1] {
+            "-" | "" => None,
+            token => Some(token),
+        };
+        if let Some((vol, bookmark)) = fields[0].split_once('#') {
+            builder.push_bookmark(vol, bookmark)?;
+            continue;
+        }
+        let vols: Vec<_> = fields[0].splitn(2, '@').collect();
+        match vols.len() {
+            1 => {
+                let marks = marks.remove(vols[0]).unwrap_or_default();
+                builder.push_volume(vols[0], fields[3], token, marks)
+            }
+            2 => builder.push_snap(vols[0], vols[1], !matches!(fields[2], "0" | "-")),
+            _ => panic!("Unexpected zfs output"),
+        }
+    }
+    Ok(builder.into_sets())
+}
+
+impl<'a> Stream<'a> {
+    /// The arguments to `zfs send` to send this stream of the given filesystem.
+    fn args(&self, source: &str, options: &SendOptions) -> Vec<String> {
+        // A resumed stream is sent with the same options it was started with, which are kept in
+        // the token.
+        let mut args = vec![];
+        if !matches!(self, Stream::Resume(_)) {
+            let flags = [
+                (options.raw, "-w"),
+                (options.compressed, "-c"),
+                (options.large_blocks, "-L"),
+                (options.embedded, "-e"),
+            ];
+            for (set, flag) in &flags {
+                if *set {
+                    args.push(flag.to_string());
+                }
+            }
+        }
+        match *self {
+            Stream::Full(snap) => args.push(format!("{}@{}", source, snap)),
+            Stream::Incremental(from, to) => {
+                args.push("-I".into());
+                args.push(format!("@{}", from));
+                args.push(format!("{}@{}", source, to));
+            }
+            Stream::FromBookmark(from, to) => {
+                args.push("-i".into());
+                args.push(format!("{}#{}", source, from));
+                args.push(format!("{}@{}", source, to));
+            }
+            Stream::Resume(token) => {
+                args.push("-t".into());
+                args.push(token.into());
+            }
+        }
+        args
+    }
+}
+
+/// Construct a Command appropriate for running a zfs command.  This is based on the hostname, and
+/// will run the command remotely, over ssh, for a remote ZFS.  The remote user must be able to run
+/// zfs with sudo without a password.
+fn command(host: Option<&str>) -> Command {
+    match host {
+        None => Command::new(ZFS),
+        Some(host) => {
+            // Ssh passes its input on to the remote command, which never
+            // reads it, so keep it from taking what is typed at rdump.
+            let mut cmd = Command::new("ssh");
+            cmd.args(&[host, "sudo", ZFS]).stdin(Stdio::null());
+            cmd
+        }
+    }
+}
 
-/// The number of recent ones to keep.
-const PRUNE_KEEP: usize = 10;
+impl Marks {
+    /// Read the marks of every filesystem on the host, from the `org.rdump:backup`,
+    /// `org.rdump:exclude` and `org.rdump:retention` properties.  These are read separately from
+    /// the list of filesystems, so that the value, which is last, may contain a tab.
+    fn read(host: Option<&str>) -> Result<HashMap<String, Marks>> {
+        let out = command(host)
+            .args(&[
+                "get",
+                "-H",
+                "-t",
+                "filesystem,volume",
+                "-o",
+                "name,property,value",
+                "org.rdump:backup,org.rdump:exclude,org.rdump:retention",
+            ])
+            .stderr(Stdio::inherit())
+            .checked_output()?;
+        Marks::parse(&out.stdout)
+    }
+
+    /// Decode the output of `zfs get -H -o name,property,value`, of the mark properties.
+    fn parse(buf: &[u8]) -> Result<HashMap<String, Marks>> {
+        let mut marks: HashMap<String, Marks> = HashMap::new();
+        for line in BufReader::new(buf).lines() {
+            let line = line?;
+            let fields: Vec<_> = line.splitn(3, '\t').collect();
+            if fields.len() != 3 {
+                return Err(anyhow!("zfs get line doesn't have 3 fields: {:?}", line));
+            }
+            marks
+                .entry(fields[0].to_owned())
+                .or_default()
+                .set(fields[0], fields[1], fields[2]);
+        }
+        Ok(marks)
+    }
+
+    /// Set the mark given by the value of a property of the named filesystem.  Unset properties
+    /// are `-`.  An invalid value is warned about, and ignored, rather than failing every
+    /// replication and prune on the host.
+    fn set(&mut self, name: &str, property: &str, value: &str) {
+        if let "-" | "" = value {
+            return;
+        }
+        let flag = match value {
+            "on" | "true" | "yes" => Some(true),
+            "off" | "false" | "no" => Some(false),
+            _ => None,
+        };
+        match (property, flag) {
+            ("org.rdump:backup", Some(flag)) => self.backup = Some(flag),
+            ("org.rdump:exclude", Some(flag)) => self.exclude = flag,
+            ("org.rdump:retention", _) => self.retention = Some(value.to_owned()),
+            _ => warn!("{}: ignoring invalid {} value {:?}", name, property, value),
+        }
+    }
+
+    /// Should this filesystem be replicated?  When `opt_in`, only those marked for backup are.
+    fn wanted(&self, opt_in: bool) -> bool {
+        !self.exclude && self.backup.unwrap_or(!opt_in)
+    }
+
+    /// The retention policy given by the `org.rdump:retention` property, if any.
+    fn retention(&self) -> Result<Option<Retention>> {
+        match self.retention {
+            Some(ref text) => serde_yaml::from_str(text)
+                .map(Some)
+                .map_err(|e| anyhow!("invalid org.rdump:retention {:?}: {}", text, e)),
+            None => Ok(None),
+        }
+    }
+}
 
 /// A `SnapBuilder` is used to build up the snapshot view of filesystems.
 struct SnapBuilder {
@@ -620,15 +988,19 @@ impl SnapBuilder {
         self.work
     }
 
-    fn push_volume(&mut self, name: &str, mount: &str) {
+    fn push_volume(&mut self, name: &str, mount: &str, token: Option<&str>, marks: Marks) {
         self.work.push(Filesystem {
             name: name.to_owned(),
             snaps: vec![],
+            bookmarks: vec![],
+            held: vec![],
             mount: mount.to_owned(),
+            resume_token: token.map(|t| t.to_owned()),
+            marks,
         });
     }
 
-    fn push_snap(&mut self, name: &str, snap: &str) {
+    fn push_snap(&mut self, name: &str, snap: &str, held: bool) {
         let pos = self.work.len();
         if pos == 0 {
             panic!("Got snapshot from zfs before volume");
@@ -638,6 +1010,21 @@ impl SnapBuilder {
             panic!("Got snapshot from zfs without same volume name");
         }
         set.snaps.push(snap.to_owned());
+        if held {
+            set.held.push(snap.to_owned());
+        }
+    }
+
+    fn push_bookmark(&mut self, name: &str, bookmark: &str) -> Result<()> {
+        // Bookmarks aren't necessarily listed right after their volume, so look for it.
+        let set = self
+            .work
+            .iter_mut()
+            .rev()
+            .find(|fs| fs.name == name)
+            .ok_or_else(|| anyhow!("Got bookmark from zfs before volume: {}#{}", name, bookmark))?;
+        set.bookmarks.push(bookmark.to_owned());
+        Ok(())
     }
 }
 
@@ -669,7 +1056,7 @@ impl Exclusions {
 }
 
 /// Humanize sizes with base-2 SI-like prefixes.
-fn humanize_size(size: usize) -> String {
+pub(crate) fn humanize_size(size: usize) -> String {
     // This unit table covers at least 80 bits, so the later ones will never be used.
     static UNITS: &'static [&'static str] = &[
         "B  ", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB", "ZiB", "YiB",
@@ -693,3 +1080,136 @@ fn humanize_size(size: usize) -> String {
 
     format!("{:6.*}{}", precision, value, UNITS[unit])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Join the fields of each line with tabs, as zfs does with `-H`.
+    fn output(lines: &[&[&str]]) -> Vec<u8> {
+        let mut buf = String::new();
+        for line in lines {
+            buf.push_str(&line.join("\t"));
+            buf.push('\n');
+        }
+        buf.into_bytes()
+    }
+
+    #[test]
+    fn list() {
+        let buf = output(&[
+            &["pool", "-", "-", "/pool"],
+            &["pool/home", "-", "-", "/home/with\ttab"],
+            &["pool/home@a", "-", "0", "-"],
+            &["pool/home@b", "-", "1", "-"],
+            &["pool/home@c", "-", "-", "-"],
+            &["pool/backup", "1-abc-def", "-", "none"],
+            &["pool/home#a", "-", "-", "-"],
+        ]);
+        let mut marks = HashMap::new();
+        marks.insert(
+            "pool/home".to_string(),
+            Marks {
+                backup: Some(true),
+                ..Marks::default()
+            },
+        );
+        let fss = parse_list(&buf, marks).unwrap();
+        let names: Vec<_> = fss.iter().map(|fs| fs.name.as_str()).collect();
+        assert_eq!(names, vec!["pool", "pool/home", "pool/backup"]);
+
+        let home = &fss[1];
+        assert_eq!(home.mount, "/home/with\ttab");
+        assert_eq!(home.snaps, vec!["a", "b", "c"]);
+        assert_eq!(home.held, vec!["b"]);
+        assert_eq!(home.bookmarks, vec!["a"]);
+        assert_eq!(home.resume_token, None);
+        assert_eq!(home.marks.backup, Some(true));
+
+        let backup = &fss[2];
+        assert_eq!(backup.resume_token.as_deref(), Some("1-abc-def"));
+        assert!(backup.snaps.is_empty());
+        assert_eq!(backup.marks.backup, None);
+    }
+
+    #[test]
+    fn bad_list() {
+        assert!(parse_list(&output(&[&["pool", "-", "-"]]), HashMap::new()).is_err());
+        assert!(parse_list(&output(&[&["pool#a", "-", "-", "-"]]), HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn marks() {
+        let buf = output(&[
+            &["pool", "org.rdump:backup", "off"],
+            &["pool", "org.rdump:exclude", "-"],
+            &["pool", "org.rdump:retention", "-"],
+            &["pool/home", "org.rdump:backup", "yes"],
+            &["pool/home", "org.rdump:exclude", "maybe"],
+            &["pool/home", "org.rdump:retention", "{daily: 7,\tweekly: 4}"],
+            &["pool/tmp", "org.rdump:backup", "sometimes"],
+            &["pool/tmp", "org.rdump:exclude", "true"],
+            &["pool/tmp", "org.rdump:retention", ""],
+        ]);
+        let marks = Marks::parse(&buf).unwrap();
+
+        let pool = &marks["pool"];
+        assert_eq!(pool.backup, Some(false));
+        assert!(!pool.exclude);
+        assert!(pool.retention.is_none());
+        assert!(!pool.wanted(false));
+
+        // The invalid exclude is ignored.
+        let home = &marks["pool/home"];
+        assert_eq!(home.backup, Some(true));
+        assert!(!home.exclude);
+        assert!(home.wanted(true));
+        let retention = home.retention().unwrap().unwrap();
+        assert_eq!((retention.daily, retention.weekly), (7, 4));
+
+        // The invalid backup is ignored.
+        let tmp = &marks["pool/tmp"];
+        assert_eq!(tmp.backup, None);
+        assert!(tmp.exclude);
+        assert!(!tmp.wanted(false));
+        assert!(tmp.retention().unwrap().is_none());
+
+        assert!(Marks::parse(&output(&[&["pool", "org.rdump:backup"]])).is_err());
+    }
+
+    #[test]
+    fn invalid_retention() {
+        let mut marks = Marks::default();
+        marks.set("pool", "org.rdump:retention", "{daily: 1}");
+        assert!(marks.retention().is_err());
+    }
+
+    #[test]
+    fn stream_args() {
+        let plain = SendOptions::default();
+        let all = SendOptions {
+            raw: true,
+            compressed: true,
+            large_blocks: true,
+            embedded: true,
+            ..SendOptions::default()
+        };
+        let args = |stream: Stream, options: &SendOptions| stream.args("pool/home", options);
+
+        assert_eq!(args(Stream::Full("a"), &plain), vec!["pool/home@a"]);
+        assert_eq!(
+            args(Stream::Full("a"), &all),
+            vec!["-w", "-c", "-L", "-e", "pool/home@a"]
+        );
+        assert_eq!(
+            args(Stream::Incremental("a", "c"), &plain),
+            vec!["-I", "@a", "pool/home@c"]
+        );
+        assert_eq!(
+            args(Stream::FromBookmark("a", "c"), &all),
+            vec!["-w", "-c", "-L", "-e", "-i", "pool/home#a", "pool/home@c"]
+        );
+        // The options of a resumed stream come from its token.
+        assert_eq!(args(Stream::Resume("1-abc"), &all), vec!["-t", "1-abc"]);
+    }
+}
@@ -0,0 +1,492 @@
+// SPDX-License-Identifier: Apache-2.0
+//! The pipeline that carries a zfs send to a zfs receive.
+//!
+//! The stream passes through a chain of commands, some of which may run
+//! on other hosts: the send and receive themselves, optionally compressing
+//! and decompressing on either side of the ssh connection, a buffer to
+//! smooth out the bursts of each side, and a progress meter.  The meter is
+//! `pv` if it is installed, otherwise the stream is copied through rdump
+//! itself, which then shows the progress.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Deserialize;
+use std::{
+    env,
+    io::{self, Read, Write},
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{humanize_size, SendOptions};
+use crate::cancel;
+
+/// How to compress the stream while it is sent over ssh.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// The command to compress with.
+    pub(super) fn compress(self) -> (&'static str, Vec<String>) {
+        match self {
+            Compression::Zstd => ("zstd", args(&["zstd", "-c", "-q"])),
+            Compression::Lz4 => ("lz4", args(&["lz4", "-c", "-q"])),
+        }
+    }
+
+    /// The command to decompress with.
+    pub(super) fn decompress(self) -> (&'static str, Vec<String>) {
+        match self {
+            Compression::Zstd => ("unzstd", args(&["zstd", "-d", "-c", "-q"])),
+            Compression::Lz4 => ("unlz4", args(&["lz4", "-d", "-c", "-q"])),
+        }
+    }
+}
+
+/// A chain of commands, each fed by the output of the one before.
+pub(super) struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+enum Stage {
+    Command(String, Box<Command>),
+    /// Show the progress of the stream, of the given estimated size.
+    Progress(usize),
+}
+
+impl Pipeline {
+    pub(super) fn new() -> Pipeline {
+        Pipeline { stages: vec![] }
+    }
+
+    /// Build the pipeline from a `zfs send` on one host to a `zfs receive` on another, given as
+    /// their command lines, with a meter to monitor the progress.  Either side may be remote, in
+    /// which case the stream is relayed through this host.  When both are remote, the stream is
+    /// still compressed as it passes through here, so the progress won't match the estimate.
+    pub(super) fn transfer(
+        from: Option<&str>,
+        send: Vec<String>,
+        to: Option<&str>,
+        receive: Vec<String>,
+        options: &SendOptions,
+        size: usize,
+    ) -> Pipeline {
+        let compress = options.compress.filter(|_| from.is_some() || to.is_some());
+
+        let mut sender = vec![("zfs send", send)];
+        let mut receiver = vec![];
+        if let Some(compress) = compress {
+            if from.is_some() {
+                sender.push(compress.compress());
+            }
+            if to.is_some() {
+                receiver.push(compress.decompress());
+            }
+        }
+        receiver.push(("zfs receive", receive));
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push_on(from, sender);
+        if let Some(compress) = compress {
+            if from.is_some() && to.is_none() {
+                let (name, argv) = compress.decompress();
+                pipeline.push(name, argv);
+            }
+        }
+        pipeline.progress(size);
+        if let Some(ref buffer) = options.buffer {
+            pipeline.push("mbuffer", args(&["mbuffer", "-q", "-m", buffer]));
+        }
+        if let Some(compress) = compress {
+            if from.is_none() && to.is_some() {
+                let (name, argv) = compress.compress();
+                pipeline.push(name, argv);
+            }
+        }
+        pipeline.push_on(to, receiver);
+        pipeline
+    }
+
+    /// Add commands, given as a name (for errors) and arguments, to run on
+    /// the given host.  The commands for a remote host are run as a single
+    /// pipeline over ssh, so that the stream only crosses the connection
+    /// once.
+    pub(super) fn push_on(&mut self, host: Option<&str>, commands: Vec<(&str, Vec<String>)>) {
+        match host {
+            None => {
+                for (name, argv) in commands {
+                    let mut cmd = Command::new(&argv[0]);
+                    cmd.args(&argv[1..]);
+                    self.stages.push(Stage::Command(name.into(), Box::new(cmd)));
+                }
+            }
+            Some(host) => {
+                let names: Vec<_> = commands.iter().map(|(name, _)| *name).collect();
+                let script: Vec<_> = commands
+                    .iter()
+                    .map(|(_, argv)| argv.iter().map(|a| quote(a)).collect::<Vec<_>>().join(" "))
+                    .collect();
+                let mut cmd = Command::new("ssh");
+                cmd.arg(host).arg(script.join(" | "));
+                self.stages.push(Stage::Command(
+                    format!("{} on {}", names.join(" | "), host),
+                    Box::new(cmd),
+                ));
+            }
+        }
+    }
+
+    /// Add a local command.
+    pub(super) fn push(&mut self, name: &str, argv: Vec<String>) {
+        self.push_on(None, vec![(name, argv)]);
+    }
+
+    /// Show the progress of the stream at this point.
+    pub(super) fn progress(&mut self, size: usize) {
+        if have_program("pv") {
+            self.push("pv", vec!["pv".into(), "-s".into(), size.to_string()]);
+        } else {
+            self.stages.push(Stage::Progress(size));
+        }
+    }
+
+    /// Run the pipeline to completion.  If the run is cancelled, or times
+    /// out, all of the commands are killed.
+    pub(super) fn run(self) -> Result<()> {
+        let mut names = vec![];
+        let mut children: Vec<Child> = vec![];
+        let mut meters: Vec<JoinHandle<io::Result<()>>> = vec![];
+        let mut input: Option<ChildStdout> = None;
+        let mut meter = None;
+
+        let count = self.stages.len();
+        for (index, stage) in self.stages.into_iter().enumerate() {
+            let (name, mut cmd) = match stage {
+                Stage::Command(name, cmd) => (name, cmd),
+                Stage::Progress(size) => {
+                    meter = Some(size);
+                    continue;
+                }
+            };
+
+            // The stream reaches this command directly from the one before,
+            // or is copied to it through the meter.
+            let mut copy = None;
+            match (input.take(), meter.take()) {
+                (Some(out), None) => {
+                    cmd.stdin(out);
+                }
+                (Some(out), Some(size)) => {
+                    cmd.stdin(Stdio::piped());
+                    copy = Some((out, size));
+                }
+                (None, _) => {
+                    cmd.stdin(Stdio::null());
+                }
+            }
+            if index + 1 < count {
+                cmd.stdout(Stdio::piped());
+            }
+            cmd.stderr(Stdio::inherit());
+
+            let mut child = match cancel::spawn(&mut cmd) {
+                Ok(child) => child,
+                Err(err) => {
+                    // Those already started would be left writing to a
+                    // pipe that nothing reads.
+                    drop(cmd);
+                    drop(copy);
+                    stop(&mut children, meters);
+                    return Err(anyhow!("Unable to run {}: {:#}", name, err));
+                }
+            };
+            if let Some((out, size)) = copy {
+                let stdin = child.stdin.take().expect("Child input");
+                meters.push(thread::spawn(move || show_progress(out, stdin, size)));
+            }
+            input = child.stdout.take();
+            names.push(name);
+            children.push(child);
+        }
+
+        let mut refs: Vec<_> = children.iter_mut().collect();
+        let statuses = cancel::wait_all(&mut refs)?;
+        // A failure usually makes the commands on either side of it fail
+        // as well, so report all of them.
+        let failed: Vec<_> = names
+            .iter()
+            .zip(&statuses)
+            .filter(|(_, status)| !status.success())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !failed.is_empty() {
+            return Err(anyhow!("{} error", failed.join(", ")));
+        }
+        for meter in meters {
+            meter
+                .join()
+                .expect("Progress meter panicked")
+                .map_err(|e| anyhow!("Error copying zfs stream: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Stop the commands of a pipeline that can't be completed, and wait for
+/// them and the meters copying between them.
+fn stop(children: &mut [Child], meters: Vec<JoinHandle<io::Result<()>>>) {
+    let mut refs: Vec<_> = children.iter_mut().collect();
+    if let Err(err) = cancel::kill(&mut refs) {
+        warn!("Unable to stop zfs send pipeline: {:#}", err);
+    }
+    // The meters fail once the commands on either side are gone.
+    for meter in meters {
+        let _ = meter.join();
+    }
+}
+
+/// Copy the stream, showing its progress on stderr, about once a second.
+fn show_progress(mut from: impl Read, mut to: impl Write, size: usize) -> io::Result<()> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let start = Instant::now();
+    let mut shown = start;
+    let mut done = 0;
+    loop {
+        let count = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all(&buf[..count])?;
+        done += count;
+        if shown.elapsed() >= Duration::from_secs(1) {
+            shown = Instant::now();
+            show(done, size, start.elapsed());
+        }
+    }
+    show(done, size, start.elapsed());
+    eprintln!();
+    Ok(())
+}
+
+fn show(done: usize, size: usize, elapsed: Duration) {
+    let rate = done as f64 / elapsed.as_secs_f64().max(0.001);
+    // The estimate can be low, so don't go over 100%.
+    let percent = (done * 100).checked_div(size).map_or(100, |p| p.min(100));
+    eprint!(
+        "\r{} of {} {:3}% {}/s ",
+        humanize_size(done),
+        humanize_size(size),
+        percent,
+        humanize_size(rate as usize)
+    );
+}
+
+/// Is the named program on the PATH?
+fn have_program(name: &str) -> bool {
+    match env::var_os("PATH") {
+        Some(path) => env::split_paths(&path).any(|dir| Path::new(&dir).join(name).is_file()),
+        None => false,
+    }
+}
+
+/// Quote an argument for the remote shell.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The command lines of the stages.  The meter is shown as `progress`, whether or not it is
+    /// `pv`.
+    fn stages(pipeline: &Pipeline) -> Vec<String> {
+        pipeline
+            .stages
+            .iter()
+            .map(|stage| match stage {
+                Stage::Progress(_) => "progress".to_string(),
+                Stage::Command(name, _) if name == "pv" => "progress".to_string(),
+                Stage::Command(_, cmd) => {
+                    let mut argv = vec![cmd.get_program().to_string_lossy().into_owned()];
+                    argv.extend(cmd.get_args().map(|a| a.to_string_lossy().into_owned()));
+                    argv.join(" ")
+                }
+            })
+            .collect()
+    }
+
+    fn transfer(from: Option<&str>, to: Option<&str>, options: &SendOptions) -> Vec<String> {
+        let pipeline = Pipeline::transfer(
+            from,
+            args(&["zfs", "send", "pool/a@1"]),
+            to,
+            args(&["zfs", "receive", "-s", "back/a"]),
+            options,
+            100,
+        );
+        stages(&pipeline)
+    }
+
+    fn options(compress: Option<Compression>, buffer: Option<&str>) -> SendOptions {
+        SendOptions {
+            compress,
+            buffer: buffer.map(|b| b.to_string()),
+            ..SendOptions::default()
+        }
+    }
+
+    #[test]
+    fn local() {
+        // Nothing crosses ssh, so nothing is compressed.
+        let options = options(Some(Compression::Zstd), Some("1G"));
+        assert_eq!(
+            transfer(None, None, &options),
+            vec![
+                "zfs send pool/a@1",
+                "progress",
+                "mbuffer -q -m 1G",
+                "zfs receive -s back/a"
+            ]
+        );
+        assert_eq!(
+            transfer(None, None, &SendOptions::default()),
+            vec!["zfs send pool/a@1", "progress", "zfs receive -s back/a"]
+        );
+    }
+
+    #[test]
+    fn to_remote() {
+        let options = options(Some(Compression::Zstd), Some("1G"));
+        assert_eq!(
+            transfer(None, Some("back"), &options),
+            vec![
+                "zfs send pool/a@1",
+                "progress",
+                "mbuffer -q -m 1G",
+                "zstd -c -q",
+                "ssh back zstd -d -c -q | zfs receive -s back/a"
+            ]
+        );
+    }
+
+    #[test]
+    fn from_remote() {
+        let options = options(Some(Compression::Lz4), Some("1G"));
+        assert_eq!(
+            transfer(Some("pool"), None, &options),
+            vec![
+                "ssh pool zfs send pool/a@1 | lz4 -c -q",
+                "lz4 -d -c -q",
+                "progress",
+                "mbuffer -q -m 1G",
+                "zfs receive -s back/a"
+            ]
+        );
+    }
+
+    #[test]
+    fn both_remote() {
+        // The stream stays compressed as it passes through.
+        let options = options(Some(Compression::Zstd), None);
+        assert_eq!(
+            transfer(Some("pool"), Some("back"), &options),
+            vec![
+                "ssh pool zfs send pool/a@1 | zstd -c -q",
+                "progress",
+                "ssh back zstd -d -c -q | zfs receive -s back/a"
+            ]
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        let _serial = cancel::serial();
+        assert_eq!(quote("pool/a@snap-1"), "pool/a@snap-1");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("with space"), "'with space'");
+        assert_eq!(quote("$HOME"), "'$HOME'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        // The shell gives back the original.
+        for arg in &["it's $HOME", "a b\tc", "'\"*;`x`"] {
+            let out = Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", quote(arg)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&out.stdout), *arg);
+        }
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push_on(
+            Some("host"),
+            vec![("zfs send", args(&["zfs", "send", "pool/my fs@it's $x"]))],
+        );
+        assert_eq!(
+            stages(&pipeline),
+            vec!["ssh host zfs send 'pool/my fs@it'\\''s $x'"]
+        );
+    }
+
+    #[test]
+    fn missing_stage() {
+        let _serial = cancel::serial();
+        let mut pipeline = Pipeline::new();
+        pipeline.push("sleep", args(&["sleep", "30"]));
+        pipeline.stages.push(Stage::Progress(100));
+        pipeline.push("cat", args(&["cat"]));
+        pipeline.push("missing", args(&["/nonexistent/rdump-missing"]));
+        pipeline.push("tail", args(&["tail"]));
+        let start = Instant::now();
+        let err = pipeline.run().unwrap_err();
+        assert!(
+            err.to_string().starts_with("Unable to run missing: "),
+            "{}",
+            err
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // Every child was stopped and reaped, leaving none behind, even as
+        // a zombie.
+        assert_eq!(children(&["sleep", "cat"]), Vec::<String>::new());
+    }
+
+    /// The children of this process with one of the given names.
+    fn children(names: &[&str]) -> Vec<String> {
+        let me = std::process::id().to_string();
+        let mut found = vec![];
+        for entry in std::fs::read_dir("/proc").unwrap() {
+            // Processes may exit while being read.
+            let stat = match std::fs::read_to_string(entry.unwrap().path().join("stat")) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            // The name is in parentheses, followed by the state and parent.
+            let (name, rest) = match (stat.find('('), stat.rfind(')')) {
+                (Some(open), Some(close)) => (&stat[open + 1..close], &stat[close + 1..]),
+                _ => continue,
+            };
+            let ppid = rest.split_whitespace().nth(1);
+            if ppid == Some(me.as_str()) && names.contains(&name) {
+                found.push(name.to_string());
+            }
+        }
+        found
+    }
+}